@@ -1,14 +1,41 @@
 use async_trait::async_trait;
 use base64::Engine;
-use log::{debug, info, warn};
+use bytes::Bytes;
+use http::uri::Authority;
+use http::HeaderMap;
+use log::{debug, error, info, warn};
+use pingora_core::apps::{
+    HttpPersistentSettings, HttpServerApp, HttpServerOptions, ReusedHttpStream,
+};
+use pingora_core::connectors::l4::BindTo;
+use pingora_core::modules::http::compression::{ResponseCompression, ResponseCompressionBuilder};
+use pingora_core::modules::http::HttpModules;
 use pingora_core::prelude::*;
+use pingora_core::protocols::http::compression::Algorithm;
+use pingora_core::protocols::http::ServerSession;
+use pingora_core::protocols::{Digest, ALPN};
 use pingora_core::server::configuration::Opt;
-use pingora_core::server::Server;
-use pingora_core::upstreams::peer::HttpPeer;
-use pingora_http::ResponseHeader;
-use pingora_proxy::{http_proxy_service, ProxyHttp, Session};
+use pingora_core::server::{Server, ShutdownWatch};
+use pingora_core::services::listening::Service;
+use pingora_core::upstreams::peer::{HttpPeer, Peer};
+use pingora_http::{Method, RequestHeader, ResponseHeader, Version};
+use pingora_proxy::{http_proxy_service, FailToProxy, ProxyHttp, Session};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts};
+use sha1::{Digest as _, Sha1};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::MissedTickBehavior;
 
 // ============================================================================
 // Configuration
@@ -19,21 +46,465 @@ struct ProxyConfig {
     username: String,
     password: String,
     listen_address: String,
+    expose_server_timing: bool,
+    normalize_upstream_errors: bool,
+    pool_active_limit: Option<usize>,
+    burn_detection: Option<BurnDetectionConfig>,
+    request_id_header: Option<String>,
+    require_host_header: bool,
+    admin_address: Option<SocketAddr>,
+    forward_trailers: bool,
+    maintenance: Option<MaintenanceResponse>,
+    metrics_backend: MetricsBackend,
+    warn_on_uri_fragment: bool,
+    enforce_sni_host_match: bool,
+    dns_overrides: DnsOverrides,
+    slow_ttfb_threshold: Option<Duration>,
+    accept_h2c: bool,
+    require_e2e_h2: bool,
+    default_timeouts: TimeoutProfile,
+    timeout_profiles: Vec<(String, TimeoutProfile)>,
+    rechunk_close_delimited: bool,
+    compression: Option<CompressionConfig>,
+    selection_strategy: SelectionStrategy,
+    max_concurrent_per_principal: Option<usize>,
+    retry_on_upstream_close: bool,
+    client_body_timeout: Option<Duration>,
+    mirror: Option<MirrorConfig>,
+    no_keepalive_hosts: Vec<String>,
+    self_test: Option<FailureMode>,
+    expose_retry_header: bool,
+    adaptive_weighting: bool,
+    max_conn_age: Option<Duration>,
+    allow_exclude_ip_header: bool,
+    admin_bind_failure: FailureMode,
+    retry_budget: Option<RetryBudgetConfig>,
+    forward_client_info: bool,
+    forwarded_for_max_entries: Option<usize>,
+    retry_on_upstream_silent: bool,
+    admin_live_interval: Duration,
+    fixed_source_port: Option<FixedSourcePortConfig>,
+    exit_if_all_down: Option<Duration>,
+}
+
+/// Per-egress-IP name resolution overrides, keyed by (egress IP, lowercase host).
+type DnsOverrides = HashMap<(String, String), IpAddr>;
+
+/// Where metrics are emitted, chosen by `METRICS_BACKEND`.
+enum MetricsBackend {
+    None,
+    Prometheus { listen_address: SocketAddr },
+    Statsd { address: String },
+}
+
+/// How `upstream_peer` picks an egress IP, chosen by `SELECTION_STRATEGY`.
+#[derive(Clone, Copy)]
+enum SelectionStrategy {
+    RoundRobin,
+    /// Same method and path always start from the same IP, for CDN cache locality.
+    HashPath,
+}
+
+/// Shadow destination receiving a sample of requests, from `MIRROR_TARGET`/`MIRROR_PERCENT`.
+struct MirrorConfig {
+    target: String,
+    percent: u64,
+}
+
+/// Retries allowed per destination host per interval, from `RETRY_BUDGET_PER_HOST`.
+struct RetryBudgetConfig {
+    retries: u32,
+    interval: Duration,
+}
+
+/// Local port every egress connection binds to, from `FIXED_SOURCE_PORT`.
+struct FixedSourcePortConfig {
+    port: u16,
+    /// How long a request waits for its egress IP's port before failing.
+    wait: Duration,
+}
+
+/// What to do when an optional startup step fails.
+#[derive(Clone, Copy)]
+enum FailureMode {
+    Warn,
+    Fatal,
+}
+
+/// What clients receive while `MAINTENANCE_MODE` is on.
+struct MaintenanceResponse {
+    status: u16,
+    body: String,
+    /// Redirect target, required for 3xx statuses (e.g. an external status page).
+    location: Option<String>,
+}
+
+const DEFAULT_COMPRESS_CONTENT_TYPES: &str =
+    "text/,application/json,application/javascript,application/xml,image/svg+xml";
+/// Pingora compresses with the first encoding the client lists, so every encoding it
+/// supports is enabled.
+const COMPRESSION_LEVELS: [(Algorithm, u32); 3] = [
+    (Algorithm::Gzip, 6),
+    (Algorithm::Brotli, 5),
+    (Algorithm::Zstd, 3),
+];
+
+/// Compression of uncompressed responses, enabled by `COMPRESS_RESPONSES`.
+struct CompressionConfig {
+    /// Responses with a smaller Content-Length are sent as is.
+    min_bytes: usize,
+    /// Content-type prefixes eligible for compression, e.g. `text/` or `application/json`.
+    content_types: Vec<String>,
+}
+
+/// Default upstream read timeout while `RETRY_ON_UPSTREAM_SILENT` is on.
+const SILENT_UPSTREAM_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upstream timeouts; unset fields fall back to the global defaults, then to pingora's.
+#[derive(Clone, Copy, Default)]
+struct TimeoutProfile {
+    connect: Option<Duration>,
+    read: Option<Duration>,
+    write: Option<Duration>,
+}
+
+impl TimeoutProfile {
+    fn or(self, fallback: TimeoutProfile) -> TimeoutProfile {
+        TimeoutProfile {
+            connect: self.connect.or(fallback.connect),
+            read: self.read.or(fallback.read),
+            write: self.write.or(fallback.write),
+        }
+    }
 }
 
 impl ProxyConfig {
-    fn load_from_environment() -> Self {
+    fn load_from_environment() -> Result<Self, String> {
         let ip_addresses = Self::parse_ip_pool();
         let username = env::var("PROXY_USER").unwrap_or_else(|_| "proxy_user".into());
         let password = env::var("PROXY_PASS").unwrap_or_else(|_| "proxy_pass".into());
         let listen_address = env::var("LISTEN_ADDR").unwrap_or_else(|_| "0.0.0.0:7777".into());
+        let expose_server_timing = Self::parse_flag("EXPOSE_SERVER_TIMING", false);
+        let normalize_upstream_errors = Self::parse_flag("NORMALIZE_UPSTREAM_ERRORS", false);
+        let require_host_header = Self::parse_flag("REQUIRE_HOST_HEADER", true);
+        let forward_trailers = Self::parse_flag("FORWARD_TRAILERS", true);
+        let maintenance = Self::parse_maintenance()?;
+        let metrics_backend = Self::parse_metrics_backend()?;
+        let warn_on_uri_fragment = Self::parse_flag("WARN_ON_URI_FRAGMENT", false);
+        let enforce_sni_host_match = Self::parse_flag("ENFORCE_SNI_HOST_MATCH", false);
+        let dns_overrides = Self::parse_dns_overrides()?;
+        let slow_ttfb_threshold = Self::parse_value("SLOW_TTFB_MS")?.map(Duration::from_millis);
+        let accept_h2c = Self::parse_flag("ENABLE_H2C", false);
+        let require_e2e_h2 = Self::parse_flag("REQUIRE_E2E_H2", false);
+        let mut default_timeouts = Self::parse_default_timeouts()?;
+        let timeout_profiles =
+            Self::parse_timeout_profiles(&env::var("TIMEOUT_PROFILES").unwrap_or_default())?;
+        let rechunk_close_delimited = Self::parse_flag("RECHUNK_CLOSE_DELIMITED", true);
+        let compression = Self::parse_compression()?;
+        let selection_strategy = Self::parse_selection_strategy()?;
+        let max_concurrent_per_principal = Self::parse_value("MAX_CONCURRENT_PER_PRINCIPAL")?;
+        let retry_on_upstream_close = Self::parse_flag("RETRY_ON_UPSTREAM_CLOSE", true);
+        let client_body_timeout =
+            Self::parse_value("CLIENT_BODY_TIMEOUT_MS")?.map(Duration::from_millis);
+        let mirror = Self::parse_mirror()?;
+        let no_keepalive_hosts = Self::parse_host_patterns("NO_KEEPALIVE_HOSTS");
+        let self_test = Self::parse_failure_mode("SELF_TEST_ON_START")?;
+        let expose_retry_header = Self::parse_flag("EXPOSE_RETRY_HEADER", false);
+        let adaptive_weighting = Self::parse_flag("ADAPTIVE_WEIGHTING", false);
+        let max_conn_age = Self::parse_value("MAX_CONN_AGE_SECS")?.map(Duration::from_secs);
+        let allow_exclude_ip_header = Self::parse_flag("ALLOW_EXCLUDE_IP_HEADER", false);
+        let admin_bind_failure =
+            Self::parse_failure_mode("ADMIN_BIND_FAILURE")?.unwrap_or(FailureMode::Fatal);
+        let retry_budget = Self::parse_retry_budget()?;
+        let forward_client_info = Self::parse_flag("FORWARD_CLIENT_INFO", false);
+        let forwarded_for_max_entries = Self::parse_value("XFF_MAX_ENTRIES")?;
+        let retry_on_upstream_silent = Self::parse_flag("RETRY_ON_UPSTREAM_SILENT", true);
+        if retry_on_upstream_silent {
+            // Without a read timeout a silent upstream is never detected
+            default_timeouts
+                .read
+                .get_or_insert(SILENT_UPSTREAM_READ_TIMEOUT);
+        }
+        let admin_live_interval =
+            Duration::from_millis(Self::parse_value("ADMIN_LIVE_INTERVAL_MS")?.unwrap_or(1000));
+        let fixed_source_port = Self::parse_fixed_source_port()?;
+        let exit_if_all_down = Self::parse_value("EXIT_IF_ALL_DOWN_SECS")?.map(Duration::from_secs);
+        let pool_active_limit = Self::parse_value("POOL_ACTIVE_LIMIT")?;
+        let burn_detection = Self::parse_burn_detection()?;
+        let admin_address = Self::parse_value("ADMIN_ADDR")?;
+        let request_id_header = env::var("REQUEST_ID_HEADER")
+            .ok()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
 
-        Self {
+        Ok(Self {
             ip_addresses,
             username,
             password,
             listen_address,
+            expose_server_timing,
+            normalize_upstream_errors,
+            pool_active_limit,
+            burn_detection,
+            request_id_header,
+            require_host_header,
+            admin_address,
+            forward_trailers,
+            maintenance,
+            metrics_backend,
+            warn_on_uri_fragment,
+            enforce_sni_host_match,
+            dns_overrides,
+            slow_ttfb_threshold,
+            accept_h2c,
+            require_e2e_h2,
+            default_timeouts,
+            timeout_profiles,
+            rechunk_close_delimited,
+            compression,
+            selection_strategy,
+            max_concurrent_per_principal,
+            retry_on_upstream_close,
+            client_body_timeout,
+            mirror,
+            no_keepalive_hosts,
+            self_test,
+            expose_retry_header,
+            adaptive_weighting,
+            max_conn_age,
+            allow_exclude_ip_header,
+            admin_bind_failure,
+            retry_budget,
+            forward_client_info,
+            forwarded_for_max_entries,
+            retry_on_upstream_silent,
+            admin_live_interval,
+            fixed_source_port,
+            exit_if_all_down,
+        })
+    }
+
+    /// Parses `EGRESS_DNS_OVERRIDES=host@egress_ip=address,...`, letting each egress IP see
+    /// the DNS view of its own network.
+    fn parse_dns_overrides() -> Result<DnsOverrides, String> {
+        let raw = env::var("EGRESS_DNS_OVERRIDES").unwrap_or_default();
+
+        raw.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let invalid = || {
+                    format!(
+                        "EGRESS_DNS_OVERRIDES entry '{}' must look like host@egress_ip=address.",
+                        entry
+                    )
+                };
+                let (target, address) = entry.split_once('=').ok_or_else(invalid)?;
+                let (host, egress_ip) = target.split_once('@').ok_or_else(invalid)?;
+                let address = address.trim().parse::<IpAddr>().map_err(|_| invalid())?;
+
+                Ok((
+                    (
+                        egress_ip.trim().to_string(),
+                        host.trim().to_ascii_lowercase(),
+                    ),
+                    address,
+                ))
+            })
+            .collect()
+    }
+
+    fn parse_compression() -> Result<Option<CompressionConfig>, String> {
+        if !Self::parse_flag("COMPRESS_RESPONSES", false) {
+            return Ok(None);
+        }
+
+        let content_types = env::var("COMPRESS_CONTENT_TYPES")
+            .unwrap_or_else(|_| DEFAULT_COMPRESS_CONTENT_TYPES.into())
+            .split(',')
+            .map(|content_type| content_type.trim().to_ascii_lowercase())
+            .filter(|content_type| !content_type.is_empty())
+            .collect();
+
+        Ok(Some(CompressionConfig {
+            min_bytes: Self::parse_value("COMPRESS_MIN_BYTES")?.unwrap_or(1024),
+            content_types,
+        }))
+    }
+
+    /// Parses a comma-separated list of exact hosts or `*.suffix` patterns.
+    fn parse_host_patterns(name: &str) -> Vec<String> {
+        env::var(name)
+            .unwrap_or_default()
+            .split(',')
+            .map(|pattern| pattern.trim().to_ascii_lowercase())
+            .filter(|pattern| !pattern.is_empty())
+            .collect()
+    }
+
+    fn parse_default_timeouts() -> Result<TimeoutProfile, String> {
+        let millis = |name| Ok::<_, String>(Self::parse_value(name)?.map(Duration::from_millis));
+
+        Ok(TimeoutProfile {
+            connect: millis("CONNECT_TIMEOUT_MS")?,
+            read: millis("READ_TIMEOUT_MS")?,
+            write: millis("WRITE_TIMEOUT_MS")?,
+        })
+    }
+
+    /// Parses `TIMEOUT_PROFILES=pattern=kind:ms;kind:ms,...` where a pattern is an exact host
+    /// or `*.suffix`, and kind is `connect`, `read` or `write`. The first matching entry wins.
+    fn parse_timeout_profiles(raw: &str) -> Result<Vec<(String, TimeoutProfile)>, String> {
+        raw.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let invalid = || {
+                    format!(
+                        "TIMEOUT_PROFILES entry '{}' must look like host=read:ms;connect:ms.",
+                        entry
+                    )
+                };
+                let (pattern, timeouts) = entry.split_once('=').ok_or_else(invalid)?;
+                let mut profile = TimeoutProfile::default();
+
+                for timeout in timeouts.split(';').map(str::trim) {
+                    let (kind, millis) = timeout.split_once(':').ok_or_else(invalid)?;
+                    let millis = millis.trim().parse().map_err(|_| invalid())?;
+                    let slot = match kind.trim() {
+                        "connect" => &mut profile.connect,
+                        "read" => &mut profile.read,
+                        "write" => &mut profile.write,
+                        _ => return Err(invalid()),
+                    };
+                    *slot = Some(Duration::from_millis(millis));
+                }
+
+                Ok((pattern.trim().to_ascii_lowercase(), profile))
+            })
+            .collect()
+    }
+
+    fn parse_metrics_backend() -> Result<MetricsBackend, String> {
+        let backend = env::var("METRICS_BACKEND").unwrap_or_else(|_| "none".into());
+
+        match backend.trim().to_ascii_lowercase().as_str() {
+            "" | "none" => Ok(MetricsBackend::None),
+            "prometheus" => {
+                let listen_address = Self::parse_value("METRICS_ADDR")?.ok_or(
+                    "METRICS_ADDR is required when METRICS_BACKEND=prometheus.".to_string(),
+                )?;
+                Ok(MetricsBackend::Prometheus { listen_address })
+            }
+            "statsd" => {
+                let address = env::var("STATSD_ADDR")
+                    .ok()
+                    .filter(|address| !address.trim().is_empty())
+                    .ok_or("STATSD_ADDR is required when METRICS_BACKEND=statsd.".to_string())?;
+                Ok(MetricsBackend::Statsd { address })
+            }
+            other => Err(format!(
+                "METRICS_BACKEND must be one of none, prometheus, statsd, got '{}'.",
+                other
+            )),
+        }
+    }
+
+    fn parse_selection_strategy() -> Result<SelectionStrategy, String> {
+        let strategy = env::var("SELECTION_STRATEGY").unwrap_or_else(|_| "round_robin".into());
+
+        match strategy.trim().to_ascii_lowercase().as_str() {
+            "" | "round_robin" => Ok(SelectionStrategy::RoundRobin),
+            "hash_path" => Ok(SelectionStrategy::HashPath),
+            other => Err(format!(
+                "SELECTION_STRATEGY must be one of round_robin, hash_path, got '{}'.",
+                other
+            )),
+        }
+    }
+
+    fn parse_mirror() -> Result<Option<MirrorConfig>, String> {
+        let Some(target) = env::var("MIRROR_TARGET")
+            .ok()
+            .map(|target| target.trim().to_string())
+            .filter(|target| !target.is_empty())
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(MirrorConfig {
+            target,
+            percent: Self::parse_value("MIRROR_PERCENT")?.unwrap_or(100),
+        }))
+    }
+
+    fn parse_retry_budget() -> Result<Option<RetryBudgetConfig>, String> {
+        let Some(retries) = Self::parse_value("RETRY_BUDGET_PER_HOST")? else {
+            return Ok(None);
+        };
+
+        Ok(Some(RetryBudgetConfig {
+            retries,
+            interval: Duration::from_secs(
+                Self::parse_value("RETRY_BUDGET_INTERVAL_SECS")?.unwrap_or(60),
+            ),
+        }))
+    }
+
+    fn parse_fixed_source_port() -> Result<Option<FixedSourcePortConfig>, String> {
+        let Some(port) = Self::parse_value("FIXED_SOURCE_PORT")? else {
+            return Ok(None);
+        };
+
+        Ok(Some(FixedSourcePortConfig {
+            port,
+            wait: Duration::from_millis(
+                Self::parse_value("FIXED_SOURCE_PORT_WAIT_MS")?.unwrap_or(5000),
+            ),
+        }))
+    }
+
+    /// Parses `off`, `warn` or `fatal`; `off` (the default) disables the step entirely.
+    fn parse_failure_mode(name: &str) -> Result<Option<FailureMode>, String> {
+        let mode = env::var(name).unwrap_or_default();
+
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "" | "off" => Ok(None),
+            "warn" => Ok(Some(FailureMode::Warn)),
+            "fatal" => Ok(Some(FailureMode::Fatal)),
+            other => Err(format!(
+                "{} must be one of off, warn, fatal, got '{}'.",
+                name, other
+            )),
+        }
+    }
+
+    fn parse_maintenance() -> Result<Option<MaintenanceResponse>, String> {
+        if !Self::parse_flag("MAINTENANCE_MODE", false) {
+            return Ok(None);
         }
+
+        Ok(Some(MaintenanceResponse {
+            status: Self::parse_value("MAINTENANCE_STATUS")?.unwrap_or(503),
+            body: env::var("MAINTENANCE_BODY")
+                .unwrap_or_else(|_| "Service Unavailable: proxy under maintenance".into()),
+            location: env::var("MAINTENANCE_LOCATION")
+                .ok()
+                .filter(|location| !location.trim().is_empty()),
+        }))
+    }
+
+    fn parse_burn_detection() -> Result<Option<BurnDetectionConfig>, String> {
+        let Some(blocked_ratio) = Self::parse_value("BURN_THRESHOLD")? else {
+            return Ok(None);
+        };
+
+        Ok(Some(BurnDetectionConfig {
+            blocked_ratio,
+            window: Self::parse_value("BURN_WINDOW")?.unwrap_or(20),
+            min_samples: Self::parse_value("BURN_MIN_SAMPLES")?.unwrap_or(10),
+            cooldown: Duration::from_secs(Self::parse_value("BURN_COOLDOWN_SECS")?.unwrap_or(300)),
+        }))
     }
 
     fn parse_ip_pool() -> Vec<String> {
@@ -45,224 +516,4352 @@ impl ProxyConfig {
             .collect()
     }
 
+    fn parse_flag(name: &str, default: bool) -> bool {
+        env::var(name)
+            .map(|value| {
+                matches!(
+                    value.trim().to_ascii_lowercase().as_str(),
+                    "1" | "true" | "yes" | "on"
+                )
+            })
+            .unwrap_or(default)
+    }
+
+    fn parse_value<T: FromStr>(name: &str) -> Result<Option<T>, String> {
+        match env::var(name) {
+            Ok(value) if !value.trim().is_empty() => value
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("{} has an invalid value '{}'.", name, value)),
+            _ => Ok(None),
+        }
+    }
+
+    /// IPs used for selection: the first `POOL_ACTIVE_LIMIT` entries, or the whole pool.
+    fn active_ip_addresses(&self) -> &[String] {
+        let limit = self
+            .pool_active_limit
+            .unwrap_or(self.ip_addresses.len())
+            .min(self.ip_addresses.len());
+        &self.ip_addresses[..limit]
+    }
+
+    /// IPs loaded from the pool but held back by `POOL_ACTIVE_LIMIT`.
+    fn reserve_ip_addresses(&self) -> &[String] {
+        &self.ip_addresses[self.active_ip_addresses().len()..]
+    }
+
     fn validate(&self) -> Result<(), String> {
         if self.ip_addresses.is_empty() {
             return Err("IP_POOL is empty. Please set IP_POOL environment variable.".into());
         }
 
+        if let Some(ip) = self
+            .ip_addresses
+            .iter()
+            .find(|ip| ip.parse::<IpAddr>().is_err())
+        {
+            return Err(format!("IP_POOL entry '{}' is not a valid IP address.", ip));
+        }
+
         if self.username.is_empty() {
             return Err("PROXY_USER cannot be empty.".into());
         }
 
+        // Basic credentials split at the first colon, so it cannot be part of the username
+        if self.username.contains(':') {
+            return Err("PROXY_USER cannot contain ':'.".into());
+        }
+
         if self.password.is_empty() {
             return Err("PROXY_PASS cannot be empty.".into());
         }
 
+        if let Some(limit) = self.pool_active_limit {
+            if limit == 0 || limit > self.ip_addresses.len() {
+                return Err(format!(
+                    "POOL_ACTIVE_LIMIT must be between 1 and the IP_POOL size ({}), got {}.",
+                    self.ip_addresses.len(),
+                    limit
+                ));
+            }
+        }
+
+        if let Some(name) = &self.request_id_header {
+            if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(format!(
+                    "REQUEST_ID_HEADER '{}' is not a valid header name.",
+                    name
+                ));
+            }
+        }
+
+        if let Some((egress_ip, _)) = self
+            .dns_overrides
+            .keys()
+            .find(|(egress_ip, _)| !self.ip_addresses.contains(egress_ip))
+        {
+            return Err(format!(
+                "EGRESS_DNS_OVERRIDES references {} which is not in IP_POOL.",
+                egress_ip
+            ));
+        }
+
+        if let Some(maintenance) = &self.maintenance {
+            if !(300..=599).contains(&maintenance.status) {
+                return Err("MAINTENANCE_STATUS must be a 3xx, 4xx or 5xx status.".into());
+            }
+
+            if (300..=399).contains(&maintenance.status) && maintenance.location.is_none() {
+                return Err(
+                    "MAINTENANCE_LOCATION is required for a 3xx MAINTENANCE_STATUS.".into(),
+                );
+            }
+        }
+
+        if let Some(burn) = &self.burn_detection {
+            if !(burn.blocked_ratio > 0.0 && burn.blocked_ratio <= 1.0) {
+                return Err("BURN_THRESHOLD must be a ratio in (0, 1].".into());
+            }
+
+            if burn.min_samples == 0 || burn.min_samples > burn.window {
+                return Err("BURN_MIN_SAMPLES must be between 1 and BURN_WINDOW.".into());
+            }
+        }
+
+        if let Some(limit) = self.exit_if_all_down {
+            if limit.is_zero() {
+                return Err("EXIT_IF_ALL_DOWN_SECS must be at least 1.".into());
+            }
+            // Quarantine is the only health signal, so without it no IP is ever down
+            if self.burn_detection.is_none() {
+                return Err("EXIT_IF_ALL_DOWN_SECS requires BURN_THRESHOLD.".into());
+            }
+        }
+
+        if let Some(mirror) = &self.mirror {
+            if mirror.percent > 100 {
+                return Err("MIRROR_PERCENT must be between 0 and 100.".into());
+            }
+        }
+
+        if self
+            .retry_budget
+            .as_ref()
+            .is_some_and(|budget| budget.interval.is_zero())
+        {
+            return Err("RETRY_BUDGET_INTERVAL_SECS must be at least 1.".into());
+        }
+
+        if self
+            .fixed_source_port
+            .as_ref()
+            .is_some_and(|fixed| fixed.port == 0)
+        {
+            return Err("FIXED_SOURCE_PORT must be between 1 and 65535.".into());
+        }
+
+        if self.admin_live_interval.is_zero() {
+            return Err("ADMIN_LIVE_INTERVAL_MS must be at least 1.".into());
+        }
+
+        if self.forwarded_for_max_entries == Some(0) {
+            return Err("XFF_MAX_ENTRIES must be at least 1.".into());
+        }
+
+        if self.max_concurrent_per_principal == Some(0) {
+            return Err("MAX_CONCURRENT_PER_PRINCIPAL must be at least 1.".into());
+        }
+
+        // The listener is plaintext, so h2c is the only way a client can arrive over HTTP/2
+        if self.require_e2e_h2 && !self.accept_h2c {
+            return Err("REQUIRE_E2E_H2 has no effect unless ENABLE_H2C is set.".into());
+        }
+
         Ok(())
     }
 }
 
 // ============================================================================
-// Proxy Implementation
+// IP Burn Detection
 // ============================================================================
 
-pub struct MultiIPProxy {
-    ip_addresses: Vec<String>,
-    request_counter: AtomicUsize,
-    expected_auth_header: String,
+struct BurnDetectionConfig {
+    /// Fraction of recent responses that must be 403/429 to quarantine an IP.
+    blocked_ratio: f64,
+    /// Number of most recent responses considered per IP.
+    window: usize,
+    /// Responses required in the window before the ratio is evaluated.
+    min_samples: usize,
+    cooldown: Duration,
 }
 
-impl MultiIPProxy {
-    fn new(ip_addresses: Vec<String>, username: &str, password: &str) -> Self {
-        let expected_auth_header = Self::create_basic_auth_header(username, password);
+#[derive(Default)]
+struct IpBlockState {
+    recent_blocked: VecDeque<bool>,
+    quarantined_until: Option<Instant>,
+}
 
-        info!("Proxy initialized with {} IP addresses", ip_addresses.len());
-        debug!("Available IPs: {:?}", ip_addresses);
+/// Pulls egress IPs whose recent responses are dominated by 403/429, which usually means the
+/// target has started blocking them.
+struct BurnDetector {
+    config: BurnDetectionConfig,
+    states: Mutex<HashMap<String, IpBlockState>>,
+}
 
+impl BurnDetector {
+    fn new(config: BurnDetectionConfig) -> Self {
         Self {
-            ip_addresses,
-            request_counter: AtomicUsize::new(0),
-            expected_auth_header,
+            config,
+            states: Mutex::new(HashMap::new()),
         }
     }
 
-    fn create_basic_auth_header(username: &str, password: &str) -> String {
-        let credentials = format!("{}:{}", username, password);
-        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
-        format!("Basic {}", encoded)
-    }
+    fn record_response(&self, ip: &str, status_code: u16) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(ip.to_string()).or_default();
+
+        state
+            .recent_blocked
+            .push_back(matches!(status_code, 403 | 429));
+        if state.recent_blocked.len() > self.config.window {
+            state.recent_blocked.pop_front();
+        }
+
+        let samples = state.recent_blocked.len();
+        let blocked = state.recent_blocked.iter().filter(|b| **b).count();
+        if samples < self.config.min_samples
+            || (blocked as f64 / samples as f64) < self.config.blocked_ratio
+        {
+            return;
+        }
 
-    fn select_next_ip(&self) -> &str {
-        let request_number = self.request_counter.fetch_add(1, Ordering::Relaxed);
-        let ip_index = request_number % self.ip_addresses.len();
-        &self.ip_addresses[ip_index]
+        warn!(
+            "IP {} quarantined for {}s: {}/{} recent responses were 403/429",
+            ip,
+            self.config.cooldown.as_secs(),
+            blocked,
+            samples
+        );
+        state.recent_blocked.clear();
+        state.quarantined_until = Some(Instant::now() + self.config.cooldown);
     }
 
-    fn verify_authentication(&self, auth_header: Option<&str>) -> bool {
-        match auth_header {
-            Some(header) => header == self.expected_auth_header,
+    fn is_quarantined(&self, ip: &str) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let Some(state) = states.get_mut(ip) else {
+            return false;
+        };
+
+        match state.quarantined_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                info!("IP {} released from quarantine", ip);
+                state.quarantined_until = None;
+                false
+            }
             None => false,
         }
     }
+
+    fn quarantine_remaining(&self, ip: &str) -> Option<Duration> {
+        let states = self.states.lock().unwrap();
+        states
+            .get(ip)
+            .and_then(|state| state.quarantined_until)
+            .and_then(|until| until.checked_duration_since(Instant::now()))
+    }
+}
+
+/// Tracks how long every egress IP has been quarantined at once.
+struct AllDownWatch {
+    ip_addresses: Vec<String>,
+    limit: Duration,
+    all_down_since: Option<Instant>,
+}
+
+impl AllDownWatch {
+    fn new(ip_addresses: Vec<String>, limit: Duration) -> Self {
+        Self {
+            ip_addresses,
+            limit,
+            all_down_since: None,
+        }
+    }
+
+    /// Whether every IP has stayed quarantined for at least `limit` as of `now`. Any IP
+    /// leaving quarantine restarts the clock.
+    fn limit_exceeded(&mut self, burn_detector: &BurnDetector, now: Instant) -> bool {
+        let all_down = self
+            .ip_addresses
+            .iter()
+            .all(|ip| burn_detector.is_quarantined(ip));
+        if !all_down {
+            self.all_down_since = None;
+            return false;
+        }
+
+        let since = *self.all_down_since.get_or_insert_with(|| {
+            warn!("All egress IPs are quarantined");
+            now
+        });
+        now.saturating_duration_since(since) >= self.limit
+    }
 }
 
 // ============================================================================
-// HTTP Proxy Implementation
+// Egress Statistics
 // ============================================================================
 
-#[async_trait]
-impl ProxyHttp for MultiIPProxy {
-    type CTX = ();
+/// Passive per-IP counters fed from completed requests.
+#[derive(Default)]
+struct IpStats {
+    active_requests: AtomicUsize,
+    /// Index 0 counts failed attempts without a response, 1..=5 count 1xx..5xx.
+    responses_by_class: [AtomicU64; 6],
+    latency_micros_total: AtomicU64,
+    latency_samples: AtomicU64,
+    /// Exponentially weighted failure rate, as `f64` bits (0.0 until a failure is seen).
+    failure_rate_bits: AtomicU64,
+}
 
-    fn new_ctx(&self) -> Self::CTX {}
+/// Floor on an IP's adaptive weight, so a struggling IP still gets traffic to recover on.
+const MIN_ADAPTIVE_WEIGHT: f64 = 0.05;
+/// Weight of the latest outcome in the rolling failure rate.
+const FAILURE_RATE_ALPHA: f64 = 0.05;
 
-    async fn upstream_peer(
-        &self,
-        session: &mut Session,
-        _ctx: &mut Self::CTX,
-    ) -> Result<Box<HttpPeer>> {
-        let source_ip = self.select_next_ip();
-        let target_info = extract_target_info(session);
+struct EgressStats {
+    ips: HashMap<String, IpStats>,
+}
 
-        debug!(
-            "Routing request to {}:{} via IP {}",
-            target_info.host, target_info.port, source_ip
-        );
+impl EgressStats {
+    fn new(ip_addresses: &[String]) -> Self {
+        let ips = ip_addresses
+            .iter()
+            .map(|ip| (ip.clone(), IpStats::default()))
+            .collect();
+        Self { ips }
+    }
 
-        let peer = create_http_peer(&target_info);
-        Ok(Box::new(peer))
+    fn request_started(&self, ip: &str) {
+        if let Some(stats) = self.ips.get(ip) {
+            stats.active_requests.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
-    async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool> {
-        let auth_header = extract_auth_header(session);
+    fn active_requests(&self, ip: &str) -> usize {
+        self.ips
+            .get(ip)
+            .map_or(0, |stats| stats.active_requests.load(Ordering::Relaxed))
+    }
 
-        if self.verify_authentication(auth_header) {
-            return Ok(false); // Allow request to proceed
+    /// Releases an attempt's active slot without recording an outcome for the IP.
+    fn request_cancelled(&self, ip: &str) {
+        if let Some(stats) = self.ips.get(ip) {
+            stats.active_requests.fetch_sub(1, Ordering::Relaxed);
         }
+    }
+
+    fn request_finished(&self, ip: &str, status_code: u16, latency: Option<Duration>) {
+        let Some(stats) = self.ips.get(ip) else {
+            return;
+        };
+
+        stats.active_requests.fetch_sub(1, Ordering::Relaxed);
 
-        warn!("Unauthorized access attempt");
-        send_auth_required_response(session).await?;
+        let class = match status_code {
+            100..=599 => usize::from(status_code / 100),
+            _ => 0,
+        };
+        stats.responses_by_class[class].fetch_add(1, Ordering::Relaxed);
 
-        Ok(true) // Stop request processing
+        // Same notion of failure as burn detection, plus attempts that got no response at all
+        let failed = matches!(status_code, 0 | 403 | 429 | 500..=599);
+        let _ =
+            stats
+                .failure_rate_bits
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                    let rate = f64::from_bits(bits);
+                    let sample = if failed { 1.0 } else { 0.0 };
+                    Some((rate + FAILURE_RATE_ALPHA * (sample - rate)).to_bits())
+                });
+
+        if let Some(latency) = latency {
+            let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+            stats
+                .latency_micros_total
+                .fetch_add(micros, Ordering::Relaxed);
+            stats.latency_samples.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
-    async fn logging(&self, session: &mut Session, _error: Option<&Error>, _ctx: &mut Self::CTX) {
-        let status_code = get_response_status(session);
-        let method = &session.req_header().method;
-        let uri = &session.req_header().uri;
+    /// Rolling share of recent upstream attempts through the IP that succeeded.
+    fn success_rate(&self, ip: &str) -> f64 {
+        self.ips.get(ip).map_or(1.0, |stats| {
+            1.0 - f64::from_bits(stats.failure_rate_bits.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Renders every IP's counters and quarantine state as one JSON document.
+    fn snapshot_json(&self, ip_order: &[String], burn_detector: Option<&BurnDetector>) -> String {
+        let entries: Vec<String> = ip_order
+            .iter()
+            .filter_map(|ip| self.ips.get(ip).map(|stats| (ip, stats)))
+            .map(|(ip, stats)| {
+                let counts: Vec<u64> = stats
+                    .responses_by_class
+                    .iter()
+                    .map(|count| count.load(Ordering::Relaxed))
+                    .collect();
+                let samples = stats.latency_samples.load(Ordering::Relaxed);
+                let avg_latency_ms = if samples == 0 {
+                    "null".to_string()
+                } else {
+                    let total = stats.latency_micros_total.load(Ordering::Relaxed);
+                    format!("{:.3}", total as f64 / samples as f64 / 1000.0)
+                };
+                let quarantine_remaining =
+                    burn_detector.and_then(|detector| detector.quarantine_remaining(ip));
+
+                let responses = format!(
+                    "{{\"1xx\":{},\"2xx\":{},\"3xx\":{},\"4xx\":{},\"5xx\":{},\"failed\":{}}}",
+                    counts[1], counts[2], counts[3], counts[4], counts[5], counts[0]
+                );
+                let fields = [
+                    format!("\"ip\":\"{}\"", escape_json(ip)),
+                    format!(
+                        "\"active_requests\":{}",
+                        stats.active_requests.load(Ordering::Relaxed)
+                    ),
+                    format!("\"responses\":{}", responses),
+                    format!("\"avg_latency_ms\":{}", avg_latency_ms),
+                    format!("\"quarantined\":{}", quarantine_remaining.is_some()),
+                    format!(
+                        "\"quarantine_remaining_secs\":{}",
+                        quarantine_remaining.map_or(0, |remaining| remaining.as_secs())
+                    ),
+                ];
+                format!("{{{}}}", fields.join(","))
+            })
+            .collect();
 
-        info!("{} {} -> {}", method, uri, status_code);
+        format!("{{\"ips\":[{}]}}", entries.join(","))
+    }
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
     }
+    escaped
 }
 
 // ============================================================================
-// Helper Functions
+// Principal Concurrency Limits
 // ============================================================================
 
-struct TargetInfo {
-    host: String,
-    port: u16,
-    use_tls: bool,
+/// Caps simultaneous in-flight requests per principal (the authenticated username).
+struct ConcurrencyLimiter {
+    limit: usize,
+    active: Mutex<HashMap<String, usize>>,
 }
 
-fn extract_target_info(session: &Session) -> TargetInfo {
-    let uri = &session.req_header().uri;
-    let host = uri
-        .authority()
-        .map(|a| a.as_str())
-        .unwrap_or("localhost")
-        .to_string();
+impl ConcurrencyLimiter {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
 
-    let use_tls = uri.scheme_str() == Some("https");
-    let default_port = if use_tls { 443 } else { 80 };
-    let port = uri.port_u16().unwrap_or(default_port);
+    /// Claims a slot for the principal, or returns false if it is already at the limit.
+    fn try_acquire(&self, principal: &str) -> bool {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(principal.to_string()).or_default();
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
 
-    TargetInfo {
-        host,
-        port,
-        use_tls,
+    fn release(&self, principal: &str) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(principal) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                active.remove(principal);
+            }
+        }
     }
 }
 
-fn create_http_peer(target: &TargetInfo) -> HttpPeer {
-    let address = format!("{}:{}", target.host, target.port);
-    HttpPeer::new(&address, target.use_tls, target.host.clone())
-}
+// ============================================================================
+// Fixed Source Port
+// ============================================================================
 
-fn extract_auth_header(session: &Session) -> Option<&str> {
-    session
-        .req_header()
-        .headers
-        .get("Proxy-Authorization")
-        .and_then(|value| value.to_str().ok())
+/// Binds every egress connection to `FIXED_SOURCE_PORT` on its egress IP. Only one connection
+/// per IP can hold the port, so requests queue per IP for up to the configured wait. Sockets
+/// are never pooled and are closed with a reset, since a TIME_WAIT socket would hold the port
+/// for minutes after every request.
+struct FixedSourcePort {
+    config: FixedSourcePortConfig,
+    locks: HashMap<String, Arc<Semaphore>>,
 }
 
-async fn send_auth_required_response(session: &mut Session) -> Result<()> {
-    let mut response = ResponseHeader::build(407, None)?;
-    response.insert_header("Proxy-Authenticate", "Basic realm=\"Proxy\"")?;
-    response.insert_header("Content-Type", "text/plain")?;
+impl FixedSourcePort {
+    fn new(config: FixedSourcePortConfig, ip_addresses: &[String]) -> Self {
+        Self {
+            config,
+            locks: ip_addresses
+                .iter()
+                .map(|ip| (ip.clone(), Arc::new(Semaphore::new(1))))
+                .collect(),
+        }
+    }
 
-    session
-        .write_response_header(Box::new(response), false)
-        .await?;
-    session
-        .write_response_body(Some(b"Proxy Authentication Required".as_ref().into()), true)
-        .await?;
+    /// Waits for the IP's port to be free; None once the configured wait has passed.
+    async fn acquire(&self, ip: &str) -> Option<OwnedSemaphorePermit> {
+        let lock = self.locks.get(ip)?.clone();
+        tokio::time::timeout(self.config.wait, lock.acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
 
-    Ok(())
+    fn apply(&self, peer: &mut HttpPeer, ip: &str) {
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            return;
+        };
+
+        let mut bind_to = BindTo::default();
+        bind_to.addr = Some(SocketAddr::new(ip, self.config.port));
+        peer.options.bind_to = Some(bind_to);
+        peer.options.idle_timeout = Some(Duration::ZERO);
+        peer.options.upstream_tcp_sock_tweak_hook = Some(Arc::new(reset_on_close));
+    }
 }
 
-fn get_response_status(session: &Session) -> u16 {
-    session
-        .response_written()
-        .map(|response| response.status.as_u16())
-        .unwrap_or(0)
+/// Makes closing the socket send a reset instead of leaving it in TIME_WAIT.
+// A zero linger never blocks on drop, which is what the deprecation warns about
+#[allow(deprecated)]
+fn reset_on_close(socket: &TcpSocket) -> Result<()> {
+    socket
+        .set_linger(Some(Duration::ZERO))
+        .or_err(SocketError, "failed to set SO_LINGER")
 }
 
 // ============================================================================
-// Application Entry Point
+// Retry Budgets
 // ============================================================================
 
-fn main() {
-    initialize_logger();
+/// Past this many tracked hosts, hosts whose bucket has fully refilled are forgotten.
+const RETRY_BUDGET_MAX_HOSTS: usize = 1024;
 
-    let config = ProxyConfig::load_from_environment();
+/// Per-host token buckets bounding how many retries the proxy sends to each destination,
+/// so failover cannot turn a struggling origin's errors into a retry storm.
+struct RetryBudget {
+    config: RetryBudgetConfig,
+    /// Remaining retry tokens per host and when they were last refilled.
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
 
-    if let Err(error_message) = config.validate() {
-        eprintln!("Configuration Error: {}", error_message);
-        std::process::exit(1);
+impl RetryBudget {
+    fn new(config: RetryBudgetConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
     }
 
-    log_startup_info(&config);
+    /// Spends one retry token for the host, or returns false if its budget is exhausted.
+    /// Tokens refill continuously, `retries` per `interval`.
+    fn try_consume(&self, host: &str) -> bool {
+        let capacity = f64::from(self.config.retries);
+        let refill_per_sec = capacity / self.config.interval.as_secs_f64();
+        let now = Instant::now();
 
-    let server = start_proxy_server(config);
-    server.run_forever();
-}
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= RETRY_BUDGET_MAX_HOSTS && !buckets.contains_key(host) {
+            let interval = self.config.interval;
+            buckets.retain(|_, (_, refilled_at)| now.duration_since(*refilled_at) < interval);
+        }
 
-fn initialize_logger() {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-}
+        let (tokens, refilled_at) = buckets.entry(host.to_string()).or_insert((capacity, now));
+        let elapsed = now.duration_since(*refilled_at).as_secs_f64();
+        *tokens = (*tokens + elapsed * refill_per_sec).min(capacity);
+        *refilled_at = now;
 
-fn log_startup_info(config: &ProxyConfig) {
-    info!("Starting Pingora Multi-IP Proxy");
-    info!("Listen address: {}", config.listen_address);
-    info!("IP pool size: {}", config.ip_addresses.len());
-    info!("Authentication: enabled");
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
 }
 
-fn start_proxy_server(config: ProxyConfig) -> Server {
-    let mut server = Server::new(Some(Opt::default())).expect("Failed to create server");
+// ============================================================================
+// Traffic Mirroring
+// ============================================================================
 
-    server.bootstrap();
+/// Request headers that describe the client hop or a body the mirror does not replay.
+const MIRROR_SKIPPED_HEADERS: &[&str] = &[
+    "proxy-authorization",
+    "connection",
+    "keep-alive",
+    "content-length",
+    "transfer-encoding",
+];
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(5);
+/// Mirrored requests allowed in flight at once; more are dropped so a slow shadow cannot pile
+/// up tasks and sockets.
+const MIRROR_MAX_IN_FLIGHT: usize = 256;
 
-    let proxy = MultiIPProxy::new(config.ip_addresses, &config.username, &config.password);
+/// Copies a sample of bodyless requests to a shadow upstream. Mirrored requests run on their
+/// own task and their responses are discarded, so they never hold up the client.
+struct TrafficMirror {
+    config: MirrorConfig,
+    counter: AtomicU64,
+    in_flight: Arc<Semaphore>,
+}
 
-    let mut proxy_service = http_proxy_service(&server.configuration, proxy);
-    proxy_service.add_tcp(&config.listen_address);
+impl TrafficMirror {
+    fn new(config: MirrorConfig) -> Self {
+        Self {
+            config,
+            counter: AtomicU64::new(0),
+            in_flight: Arc::new(Semaphore::new(MIRROR_MAX_IN_FLIGHT)),
+        }
+    }
 
-    server.add_service(proxy_service);
+    /// Deterministic sampling spread evenly over the stream: a request is mirrored each time
+    /// the running count of `percent`/100 shares crosses a whole number.
+    fn should_mirror(&self) -> bool {
+        let request_number = self.counter.fetch_add(1, Ordering::Relaxed);
+        let percent = self.config.percent;
+        (request_number + 1) * percent / 100 > request_number * percent / 100
+    }
 
-    info!("Server ready to accept connections");
+    fn spawn(&self, request: &RequestHeader) {
+        let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+            debug!(
+                "Dropping mirror request, {} already in flight",
+                MIRROR_MAX_IN_FLIGHT
+            );
+            return;
+        };
 
-    server
+        let mut wire = format!(
+            "{} {} HTTP/1.1\r\n",
+            request.method,
+            request
+                .uri
+                .path_and_query()
+                .map_or("/", |path_and_query| path_and_query.as_str())
+        )
+        .into_bytes();
+        for (name, value) in request.headers.iter() {
+            if MIRROR_SKIPPED_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            wire.extend_from_slice(name.as_str().as_bytes());
+            wire.extend_from_slice(b": ");
+            wire.extend_from_slice(value.as_bytes());
+            wire.extend_from_slice(b"\r\n");
+        }
+        wire.extend_from_slice(b"Connection: close\r\n\r\n");
+
+        let target = self.config.target.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let exchange = async {
+                let mut stream = TcpStream::connect(&target).await?;
+                stream.write_all(&wire).await?;
+                // Drain the response so the shadow sees a normal exchange
+                tokio::io::copy(&mut stream, &mut tokio::io::sink()).await
+            };
+            match tokio::time::timeout(MIRROR_TIMEOUT, exchange).await {
+                Ok(Ok(_)) => debug!("Mirrored request to {}", target),
+                Ok(Err(e)) => debug!("Mirror request to {} failed: {}", target, e),
+                Err(_) => debug!("Mirror request to {} timed out", target),
+            }
+        });
+    }
+}
+
+// ============================================================================
+// Metrics
+// ============================================================================
+
+/// Destination for proxy metrics. The proxy only talks to this trait, so backends can be
+/// swapped by configuration.
+pub trait MetricsSink: Send + Sync {
+    fn incr_counter(&self, name: &str, labels: &[(&str, &str)]);
+    fn set_gauge(&self, name: &str, value: f64, labels: &[(&str, &str)]);
+    fn observe_histogram(&self, name: &str, value: f64, labels: &[(&str, &str)]);
+}
+
+struct NoopMetrics;
+
+impl MetricsSink for NoopMetrics {
+    fn incr_counter(&self, _name: &str, _labels: &[(&str, &str)]) {}
+    fn set_gauge(&self, _name: &str, _value: f64, _labels: &[(&str, &str)]) {}
+    fn observe_histogram(&self, _name: &str, _value: f64, _labels: &[(&str, &str)]) {}
+}
+
+/// Registers metrics in the default Prometheus registry on first use, keyed by name. The
+/// label names of the first call define the metric's labels.
+#[derive(Default)]
+struct PrometheusMetrics {
+    counters: Mutex<HashMap<String, IntCounterVec>>,
+    gauges: Mutex<HashMap<String, GaugeVec>>,
+    histograms: Mutex<HashMap<String, HistogramVec>>,
+}
+
+/// Millisecond buckets for latency histograms.
+const LATENCY_BUCKETS_MS: &[f64] = &[
+    1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+impl PrometheusMetrics {
+    fn register<M: Collector + Clone + 'static>(name: &str, metric: M) -> M {
+        if let Err(error) = prometheus::register(Box::new(metric.clone())) {
+            warn!("Failed to register metric {}: {}", name, error);
+        }
+        metric
+    }
+
+    fn split_labels<'a>(labels: &[(&'a str, &'a str)]) -> (Vec<&'a str>, Vec<&'a str>) {
+        labels.iter().copied().unzip()
+    }
+}
+
+impl MetricsSink for PrometheusMetrics {
+    fn incr_counter(&self, name: &str, labels: &[(&str, &str)]) {
+        let (names, values) = Self::split_labels(labels);
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(name.to_string()).or_insert_with(|| {
+            let counter = IntCounterVec::new(Opts::new(name, name), &names)
+                .expect("invalid counter definition");
+            Self::register(name, counter)
+        });
+
+        match counter.get_metric_with_label_values(&values) {
+            Ok(counter) => counter.inc(),
+            Err(error) => warn!("Dropped counter {}: {}", name, error),
+        }
+    }
+
+    fn set_gauge(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        let (names, values) = Self::split_labels(labels);
+        let mut gauges = self.gauges.lock().unwrap();
+        let gauge = gauges.entry(name.to_string()).or_insert_with(|| {
+            let gauge =
+                GaugeVec::new(Opts::new(name, name), &names).expect("invalid gauge definition");
+            Self::register(name, gauge)
+        });
+
+        match gauge.get_metric_with_label_values(&values) {
+            Ok(gauge) => gauge.set(value),
+            Err(error) => warn!("Dropped gauge {}: {}", name, error),
+        }
+    }
+
+    fn observe_histogram(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        let (names, values) = Self::split_labels(labels);
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(name.to_string()).or_insert_with(|| {
+            let options = HistogramOpts::new(name, name).buckets(LATENCY_BUCKETS_MS.to_vec());
+            let histogram =
+                HistogramVec::new(options, &names).expect("invalid histogram definition");
+            Self::register(name, histogram)
+        });
+
+        match histogram.get_metric_with_label_values(&values) {
+            Ok(histogram) => histogram.observe(value),
+            Err(error) => warn!("Dropped histogram {}: {}", name, error),
+        }
+    }
+}
+
+/// Fire-and-forget UDP statsd client. Labels are sent as DogStatsD tags and histograms as
+/// timers (`|ms`).
+struct StatsdMetrics {
+    socket: UdpSocket,
+}
+
+impl StatsdMetrics {
+    fn connect(address: &str) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        // Never stall a proxy worker on a full socket buffer, drop the datagram instead
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+
+    fn send(&self, name: &str, value: &str, kind: &str, labels: &[(&str, &str)]) {
+        let mut line = format!("{}:{}|{}", name, value, kind);
+        if !labels.is_empty() {
+            let tags: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}:{}", key, value))
+                .collect();
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+
+        if let Err(error) = self.socket.send(line.as_bytes()) {
+            debug!("Dropped statsd metric {}: {}", name, error);
+        }
+    }
+}
+
+impl MetricsSink for StatsdMetrics {
+    fn incr_counter(&self, name: &str, labels: &[(&str, &str)]) {
+        self.send(name, "1", "c", labels);
+    }
+
+    fn set_gauge(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        self.send(name, &value.to_string(), "g", labels);
+    }
+
+    fn observe_histogram(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        self.send(name, &value.to_string(), "ms", labels);
+    }
+}
+
+fn create_metrics_sink(backend: &MetricsBackend) -> Arc<dyn MetricsSink> {
+    match backend {
+        MetricsBackend::None => Arc::new(NoopMetrics),
+        MetricsBackend::Prometheus { .. } => Arc::new(PrometheusMetrics::default()),
+        MetricsBackend::Statsd { address } => match StatsdMetrics::connect(address) {
+            Ok(statsd) => Arc::new(statsd),
+            Err(error) => {
+                warn!("Statsd disabled, cannot reach {}: {}", address, error);
+                Arc::new(NoopMetrics)
+            }
+        },
+    }
+}
+
+fn status_class_label(status_code: u16) -> &'static str {
+    match status_code {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "failed",
+    }
+}
+
+// ============================================================================
+// Admin Endpoint
+// ============================================================================
+
+/// GUID appended to `Sec-WebSocket-Key` when computing the handshake accept value (RFC 6455).
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// A live client that cannot take a snapshot within this long is disconnected.
+const LIVE_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Unparsed client data a live connection may buffer; the endpoint only expects control frames.
+const LIVE_MAX_CLIENT_BUFFER: usize = 64 * 1024;
+
+/// Close status sent to live clients when the proxy shuts down (1001, going away).
+const CLOSE_GOING_AWAY: [u8; 2] = 1001u16.to_be_bytes();
+
+/// Serves read-only operational state on `ADMIN_ADDR`.
+struct AdminApp {
+    ip_addresses: Vec<String>,
+    egress_stats: Arc<EgressStats>,
+    burn_detector: Option<Arc<BurnDetector>>,
+    /// How often `/admin/live` pushes a snapshot.
+    live_interval: Duration,
+}
+
+impl AdminApp {
+    fn snapshot_json(&self) -> String {
+        self.egress_stats
+            .snapshot_json(&self.ip_addresses, self.burn_detector.as_deref())
+    }
+
+    fn response(&self, request: &RequestHeader) -> http::Response<Vec<u8>> {
+        let (status, content_type, body) = match (&request.method, request.uri.path()) {
+            (&Method::GET, "/admin/snapshot") => (200, "application/json", self.snapshot_json()),
+            (&Method::GET, "/admin/live") => (
+                400,
+                "text/plain",
+                "Bad Request: expected a WebSocket upgrade".to_string(),
+            ),
+            _ => (404, "text/plain", "Not Found".to_string()),
+        };
+
+        http::Response::builder()
+            .status(status)
+            .header("Content-Type", content_type)
+            .header("Content-Length", body.len())
+            .body(body.into_bytes())
+            .unwrap()
+    }
+
+    /// Pushes a snapshot to a WebSocket client every `live_interval` until it closes or the
+    /// proxy shuts down. Writes are awaited and missed ticks are skipped, so a slow client
+    /// gets the latest snapshot instead of a backlog of stale ones.
+    async fn serve_live(&self, http: &mut ServerSession, accept: String, shutdown: &ShutdownWatch) {
+        let mut response = ResponseHeader::build(101, None).unwrap();
+        response.insert_header("Upgrade", "websocket").unwrap();
+        response.insert_header("Connection", "Upgrade").unwrap();
+        response
+            .insert_header("Sec-WebSocket-Accept", accept)
+            .unwrap();
+        if let Err(e) = http.write_response_header(Box::new(response)).await {
+            warn!("Failed to accept live admin client: {}", e);
+            return;
+        }
+        http.set_write_timeout(Some(LIVE_WRITE_TIMEOUT));
+
+        let mut shutdown = shutdown.clone();
+        let mut ticker = tokio::time::interval(self.live_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut received = Vec::new();
+
+        loop {
+            let mut closing = false;
+            let outgoing = tokio::select! {
+                _ = ticker.tick() => websocket_frame(0x1, self.snapshot_json().as_bytes()),
+                _ = shutdown.changed() => {
+                    closing = true;
+                    websocket_frame(0x8, &CLOSE_GOING_AWAY)
+                }
+                data = http.read_request_body() => {
+                    let Ok(Some(data)) = data else {
+                        break; // Client disconnected
+                    };
+                    received.extend_from_slice(&data);
+                    if received.len() > LIVE_MAX_CLIENT_BUFFER {
+                        warn!("Dropping live admin client sending oversized frames");
+                        break;
+                    }
+
+                    let mut replies = Vec::new();
+                    while let Some((opcode, payload)) = next_client_frame(&mut received) {
+                        match opcode {
+                            0x8 => {
+                                // Echo the close status code, then end the stream
+                                let status = &payload[..payload.len().min(2)];
+                                replies.extend(websocket_frame(0x8, status));
+                                closing = true;
+                                break;
+                            }
+                            0x9 => replies.extend(websocket_frame(0xA, &payload)),
+                            _ => {} // The stream is one way, client data is ignored
+                        }
+                    }
+                    replies
+                }
+            };
+
+            if !outgoing.is_empty() {
+                if let Err(e) = http.write_response_body(outgoing.into(), false).await {
+                    debug!("Live admin client went away: {}", e);
+                    break;
+                }
+            }
+            if closing {
+                break;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl HttpServerApp for AdminApp {
+    async fn process_new_http(
+        self: &Arc<Self>,
+        mut http: ServerSession,
+        shutdown: &ShutdownWatch,
+    ) -> Option<ReusedHttpStream> {
+        match http.read_request().await {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => {
+                warn!("Admin endpoint failed to read request: {}", e);
+                return None;
+            }
+        }
+
+        let request = http.req_header();
+        if request.method == Method::GET && request.uri.path() == "/admin/live" {
+            if let Some(accept) = websocket_accept(&http) {
+                self.serve_live(&mut http, accept, shutdown).await;
+                return None; // Upgraded connections are never reused
+            }
+        }
+
+        // Same flow as pingora's `ServeHttp` apps
+        if *shutdown.borrow() {
+            http.set_keepalive(None);
+        } else {
+            http.set_keepalive(Some(60));
+        }
+        let (parts, body) = self.response(http.req_header()).into_parts();
+        if let Err(e) = http.write_response_header(Box::new(parts.into())).await {
+            warn!("Admin endpoint failed to write response: {}", e);
+            return None;
+        }
+        if let Err(e) = http.write_response_body(body.into(), true).await {
+            warn!("Admin endpoint failed to write response: {}", e);
+            return None;
+        }
+
+        let persistent_settings = HttpPersistentSettings::for_session(&http);
+        match http.finish().await {
+            Ok(stream) => {
+                stream.map(|stream| ReusedHttpStream::new(stream, Some(persistent_settings)))
+            }
+            Err(e) => {
+                warn!("Admin endpoint failed to finish request: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// The `Sec-WebSocket-Accept` value for a valid version 13 WebSocket upgrade request.
+fn websocket_accept(http: &ServerSession) -> Option<String> {
+    let headers = &http.req_header().headers;
+    let version_ok = headers
+        .get("Sec-WebSocket-Version")
+        .is_some_and(|version| version.as_bytes() == b"13");
+    let key = headers.get("Sec-WebSocket-Key")?;
+    if !http.is_upgrade_req() || !version_ok {
+        return None;
+    }
+    Some(websocket_accept_value(key.as_bytes()))
+}
+
+fn websocket_accept_value(key: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+/// Encodes an unmasked, unfragmented server frame.
+fn websocket_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Takes the next complete client frame off `buffer` as (opcode, unmasked payload), or
+/// returns None until enough bytes have arrived.
+fn next_client_frame(buffer: &mut Vec<u8>) -> Option<(u8, Vec<u8>)> {
+    let opcode = buffer.first()? & 0x0F;
+    let second = *buffer.get(1)?;
+    let (payload_len, mut offset) = match second & 0x7F {
+        126 => (
+            u16::from_be_bytes(buffer.get(2..4)?.try_into().ok()?) as usize,
+            4,
+        ),
+        127 => (
+            u64::from_be_bytes(buffer.get(2..10)?.try_into().ok()?) as usize,
+            10,
+        ),
+        len => (len as usize, 2),
+    };
+    let mask: Option<[u8; 4]> = if second & 0x80 != 0 {
+        let mask = buffer.get(offset..offset + 4)?.try_into().ok()?;
+        offset += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    let mut payload = buffer
+        .get(offset..offset.checked_add(payload_len)?)?
+        .to_vec();
+    if let Some(mask) = mask {
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+    }
+    buffer.drain(..offset + payload_len);
+    Some((opcode, payload))
+}
+
+// ============================================================================
+// Proxy Implementation
+// ============================================================================
+
+pub struct MultiIPProxy {
+    ip_addresses: Vec<String>,
+    request_counter: AtomicUsize,
+    username: String,
+    expected_auth_header: String,
+    expose_server_timing: bool,
+    normalize_upstream_errors: bool,
+    burn_detector: Option<Arc<BurnDetector>>,
+    egress_stats: Arc<EgressStats>,
+    concurrency_limiter: Option<ConcurrencyLimiter>,
+    mirror: Option<TrafficMirror>,
+    retry_budget: Option<RetryBudget>,
+    fixed_source_port: Option<FixedSourcePort>,
+    admin_address: Option<SocketAddr>,
+    request_id_header: Option<String>,
+    request_id_counter: AtomicU64,
+    require_host_header: bool,
+    forward_trailers: bool,
+    maintenance: Option<MaintenanceResponse>,
+    metrics: Arc<dyn MetricsSink>,
+    metrics_address: Option<SocketAddr>,
+    warn_on_uri_fragment: bool,
+    enforce_sni_host_match: bool,
+    dns_overrides: DnsOverrides,
+    slow_ttfb_threshold: Option<Duration>,
+    require_e2e_h2: bool,
+    default_timeouts: TimeoutProfile,
+    timeout_profiles: Vec<(String, TimeoutProfile)>,
+    rechunk_close_delimited: bool,
+    compression: Option<CompressionConfig>,
+    selection_strategy: SelectionStrategy,
+    retry_on_upstream_close: bool,
+    client_body_timeout: Option<Duration>,
+    no_keepalive_hosts: Vec<String>,
+    expose_retry_header: bool,
+    adaptive_weighting: bool,
+    max_conn_age: Option<Duration>,
+    allow_exclude_ip_header: bool,
+    forward_client_info: bool,
+    forwarded_for_max_entries: Option<usize>,
+    retry_on_upstream_silent: bool,
+    admin_live_interval: Duration,
+}
+
+/// Outcome of checking a `Proxy-Authorization` value against the configured credentials.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthResult {
+    Authenticated { username: String },
+    Unauthenticated { reason: AuthFailure },
+}
+
+/// Why a request failed authentication, reported as the `reason` metric label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    Missing,
+    /// Present-but-blank credentials are tracked apart from a missing header, since they
+    /// usually come from misbehaving clients or probing rather than first contact.
+    Empty,
+    Invalid,
+}
+
+impl AuthFailure {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuthFailure::Missing => "missing",
+            AuthFailure::Empty => "empty",
+            AuthFailure::Invalid => "invalid",
+        }
+    }
+}
+
+impl MultiIPProxy {
+    fn new(config: ProxyConfig) -> Self {
+        let expected_auth_header =
+            Self::create_basic_auth_header(&config.username, &config.password);
+
+        let ip_addresses = config.active_ip_addresses().to_vec();
+
+        info!("Proxy initialized with {} IP addresses", ip_addresses.len());
+        debug!("Available IPs: {:?}", ip_addresses);
+
+        Self {
+            ip_addresses: ip_addresses.clone(),
+            request_counter: AtomicUsize::new(0),
+            username: config.username,
+            expected_auth_header,
+            expose_server_timing: config.expose_server_timing,
+            normalize_upstream_errors: config.normalize_upstream_errors,
+            burn_detector: config
+                .burn_detection
+                .map(|burn| Arc::new(BurnDetector::new(burn))),
+            egress_stats: Arc::new(EgressStats::new(&ip_addresses)),
+            concurrency_limiter: config
+                .max_concurrent_per_principal
+                .map(ConcurrencyLimiter::new),
+            mirror: config.mirror.map(TrafficMirror::new),
+            retry_budget: config.retry_budget.map(RetryBudget::new),
+            fixed_source_port: config
+                .fixed_source_port
+                .map(|fixed| FixedSourcePort::new(fixed, &ip_addresses)),
+            admin_address: config.admin_address,
+            request_id_header: config.request_id_header,
+            request_id_counter: AtomicU64::new(0),
+            require_host_header: config.require_host_header,
+            forward_trailers: config.forward_trailers,
+            maintenance: config.maintenance,
+            metrics: create_metrics_sink(&config.metrics_backend),
+            metrics_address: match config.metrics_backend {
+                MetricsBackend::Prometheus { listen_address } => Some(listen_address),
+                _ => None,
+            },
+            warn_on_uri_fragment: config.warn_on_uri_fragment,
+            enforce_sni_host_match: config.enforce_sni_host_match,
+            dns_overrides: config.dns_overrides,
+            slow_ttfb_threshold: config.slow_ttfb_threshold,
+            require_e2e_h2: config.require_e2e_h2,
+            default_timeouts: config.default_timeouts,
+            timeout_profiles: config.timeout_profiles,
+            rechunk_close_delimited: config.rechunk_close_delimited,
+            compression: config.compression,
+            selection_strategy: config.selection_strategy,
+            retry_on_upstream_close: config.retry_on_upstream_close,
+            client_body_timeout: config.client_body_timeout,
+            no_keepalive_hosts: config.no_keepalive_hosts,
+            expose_retry_header: config.expose_retry_header,
+            adaptive_weighting: config.adaptive_weighting,
+            max_conn_age: config.max_conn_age,
+            allow_exclude_ip_header: config.allow_exclude_ip_header,
+            forward_client_info: config.forward_client_info,
+            forwarded_for_max_entries: config.forwarded_for_max_entries,
+            retry_on_upstream_silent: config.retry_on_upstream_silent,
+            admin_live_interval: config.admin_live_interval,
+        }
+    }
+
+    fn create_basic_auth_header(username: &str, password: &str) -> String {
+        let credentials = format!("{}:{}", username, password);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        format!("Basic {}", encoded)
+    }
+
+    /// Picks the egress IP for an upstream attempt, skipping quarantined and `excluded` IPs.
+    /// `attempt` counts retries so hashed selection moves on to the next IP instead of repeating
+    /// a failure. `excluded` must leave at least one IP of the pool available.
+    fn select_next_ip(&self, request: &RequestHeader, attempt: usize, excluded: &[String]) -> &str {
+        let request_number = match self.selection_strategy {
+            SelectionStrategy::RoundRobin => self.request_counter.fetch_add(1, Ordering::Relaxed),
+            SelectionStrategy::HashPath => {
+                let mut hasher = DefaultHasher::new();
+                request.method.hash(&mut hasher);
+                request.uri.path().hash(&mut hasher);
+                (hasher.finish() as usize).wrapping_add(attempt)
+            }
+        };
+        if self.adaptive_weighting {
+            if let Some(ip) = self.select_weighted_ip(request_number, excluded) {
+                return ip;
+            }
+        }
+
+        let pool_size = self.ip_addresses.len();
+        let mut candidates = (0..pool_size)
+            .map(|offset| &self.ip_addresses[request_number.wrapping_add(offset) % pool_size])
+            .filter(|ip| !excluded.contains(ip));
+
+        candidates
+            .clone()
+            .find(|ip| !self.is_quarantined(ip))
+            .or_else(|| {
+                warn!("All IPs are quarantined, falling back to round-robin");
+                candidates.next()
+            })
+            .unwrap_or(&self.ip_addresses[request_number % pool_size])
+    }
+
+    /// Picks a non-quarantined IP with probability proportional to its recent success rate.
+    /// The base strategy's request number is spread over [0, 1) by Fibonacci hashing, so round
+    /// robin stays evenly interleaved and hash_path stays deterministic per path.
+    fn select_weighted_ip(&self, request_number: usize, excluded: &[String]) -> Option<&str> {
+        let candidates: Vec<(&str, f64)> = self
+            .ip_addresses
+            .iter()
+            .filter(|ip| !excluded.contains(ip) && !self.is_quarantined(ip))
+            .map(|ip| {
+                let weight = self.egress_stats.success_rate(ip).max(MIN_ADAPTIVE_WEIGHT);
+                (ip.as_str(), weight)
+            })
+            .collect();
+        let total_weight: f64 = candidates.iter().map(|(_, weight)| weight).sum();
+
+        let mixed = (request_number as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        let mut point = (mixed >> 11) as f64 / (1u64 << 53) as f64 * total_weight;
+
+        candidates
+            .iter()
+            .find(|(_, weight)| {
+                point -= weight;
+                point < 0.0
+            })
+            .or(candidates.last())
+            .map(|(ip, _)| *ip)
+    }
+
+    /// Cancels a pending retry once the destination host has used up its retry budget.
+    /// Retiring an over-age pooled connection is not a retry and is never charged.
+    fn enforce_retry_budget(&self, session: &Session, e: &mut Error, ctx: &RequestContext) {
+        let Some(budget) = &self.retry_budget else {
+            return;
+        };
+        if !e.retry() || ctx.retired_connection {
+            return;
+        }
+
+        let host = extract_target_info(session).host;
+        if !budget.try_consume(&host) {
+            warn!("Retry budget for {} exhausted, not retrying", host);
+            self.metrics
+                .incr_counter("proxy_retry_budget_exhausted_total", &[]);
+            e.set_retry(false);
+        }
+    }
+
+    /// Pool IPs listed in the comma-separated `X-Proxy-Exclude-IP` header. Entries outside the
+    /// pool are ignored.
+    fn excluded_ips(&self, request: &RequestHeader) -> Vec<String> {
+        let Some(header) = request
+            .headers
+            .get("X-Proxy-Exclude-IP")
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Vec::new();
+        };
+
+        self.ip_addresses
+            .iter()
+            .filter(|ip| header.split(',').any(|entry| entry.trim() == ip.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    fn is_quarantined(&self, ip: &str) -> bool {
+        self.burn_detector
+            .as_ref()
+            .is_some_and(|detector| detector.is_quarantined(ip))
+    }
+
+    /// True when the resolved upstream address is one of our own admin/metrics listeners,
+    /// which must not be reachable through the proxy. Checking the address rather than the
+    /// Host header covers every spelling of it: short and decimal IPv4 forms, IPv4-mapped IPv6,
+    /// names resolving to loopback, and this host's own interface addresses.
+    fn is_management_address(&self, address: SocketAddr) -> bool {
+        let ip = address.ip().to_canonical();
+
+        [self.admin_address, self.metrics_address]
+            .iter()
+            .flatten()
+            .filter(|listener| listener.port() == address.port())
+            .any(|listener| {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip == listener.ip().to_canonical()
+                    || is_local_address(ip)
+            })
+    }
+
+    /// Address the target resolves to from the given egress IP's network, if overridden.
+    fn resolve_override(&self, source_ip: &str, host: &str) -> Option<IpAddr> {
+        self.dns_overrides
+            .get(&(source_ip.to_string(), host.to_ascii_lowercase()))
+            .copied()
+    }
+
+    /// Whether a response may be compressed for the client. Pingora still checks Accept-Encoding
+    /// and leaves responses that already carry a Content-Encoding alone.
+    fn should_compress(&self, request: &RequestHeader, response: &ResponseHeader) -> bool {
+        let Some(compression) = &self.compression else {
+            return false;
+        };
+
+        if request.method == Method::HEAD || matches!(response.status.as_u16(), 204 | 304) {
+            return false;
+        }
+
+        let header = |name| {
+            response
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+
+        let too_small = header("Content-Length")
+            .and_then(|length| length.trim().parse::<usize>().ok())
+            .is_some_and(|length| length < compression.min_bytes);
+        let content_type = header("Content-Type")
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        !too_small
+            && compression
+                .content_types
+                .iter()
+                .any(|allowed| content_type.starts_with(allowed.as_str()))
+    }
+
+    /// Timeouts for the target host: its profile if a pattern matches, else the defaults.
+    fn timeouts_for(&self, host: &str) -> TimeoutProfile {
+        let host = host.to_ascii_lowercase();
+
+        self.timeout_profiles
+            .iter()
+            .find(|(pattern, _)| host_matches_pattern(&host, pattern))
+            .map_or(self.default_timeouts, |(_, profile)| {
+                profile.or(self.default_timeouts)
+            })
+    }
+
+    fn is_no_keepalive_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.no_keepalive_hosts
+            .iter()
+            .any(|pattern| host_matches_pattern(&host, pattern))
+    }
+
+    /// Whether upstream trailers reach the client. Pingora only carries trailers over HTTP/2,
+    /// so both hops must be h2 and the client must have sent `TE: trailers`.
+    fn relays_trailers(&self, session: &Session, response: &ResponseHeader) -> bool {
+        self.forward_trailers
+            && session.is_http2()
+            && response.version == Version::HTTP_2
+            && accepts_trailers(session.req_header())
+    }
+
+    fn log_uri_fragment(&self, location: &str) {
+        if self.warn_on_uri_fragment {
+            warn!("Stripped URI fragment from {}", location);
+        } else {
+            debug!("Stripped URI fragment from {}", location);
+        }
+    }
+
+    fn record_active_requests(&self, ip: &str) {
+        let active = self.egress_stats.active_requests(ip);
+        self.metrics
+            .set_gauge("proxy_active_requests", active as f64, &[("ip", ip)]);
+    }
+
+    fn rejects_missing_host(&self, request: &RequestHeader) -> bool {
+        self.require_host_header && is_missing_host(request)
+    }
+
+    /// Replaces an upstream 5xx with the canonical proxy 502 when `NORMALIZE_UPSTREAM_ERRORS`
+    /// is on, remembering the original status for the body filter and stats.
+    fn normalize_upstream_error(
+        &self,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut RequestContext,
+    ) -> Result<()> {
+        if !self.normalize_upstream_errors || !upstream_response.status.is_server_error() {
+            return Ok(());
+        }
+
+        let upstream_status = upstream_response.status.as_u16();
+        warn!("Normalizing upstream {} into proxy error", upstream_status);
+        *upstream_response =
+            build_upstream_error_response(upstream_status, self.request_id_tag(ctx))?;
+        ctx.normalized_upstream_status = Some(upstream_status);
+        Ok(())
+    }
+
+    /// Feeds a finished attempt's outcome into the per-IP stats, metrics and burn detection.
+    /// A normalized 5xx is recorded with the upstream's status, not the proxy's 502.
+    fn record_outcome(&self, source_ip: &str, status_code: u16, ctx: &RequestContext) {
+        let upstream_status = ctx.normalized_upstream_status.unwrap_or(status_code);
+        let latency = ctx
+            .connect_duration
+            .zip(ctx.upstream_duration)
+            .map(|(connect, upstream)| connect + upstream);
+        self.egress_stats
+            .request_finished(source_ip, upstream_status, latency);
+        self.record_active_requests(source_ip);
+
+        self.metrics.incr_counter(
+            "proxy_requests_total",
+            &[
+                ("ip", source_ip),
+                ("status", status_class_label(upstream_status)),
+            ],
+        );
+        if let Some(latency) = latency {
+            self.metrics.observe_histogram(
+                "proxy_upstream_latency_ms",
+                latency.as_secs_f64() * 1000.0,
+                &[("ip", source_ip)],
+            );
+        }
+        if let Some(ttfb) = ctx.ttfb {
+            self.metrics.observe_histogram(
+                "proxy_upstream_ttfb_ms",
+                ttfb.as_secs_f64() * 1000.0,
+                &[("ip", source_ip)],
+            );
+        }
+
+        if let Some(detector) = &self.burn_detector {
+            detector.record_response(source_ip, upstream_status);
+        }
+    }
+
+    /// Whether a connection of this age is too old to use under `MAX_CONN_AGE_SECS`. Fresh
+    /// connections are always used, however long their handshake took.
+    fn retires_connection(&self, reused: bool, age: Duration) -> bool {
+        reused && self.max_conn_age.is_some_and(|max_age| age > max_age)
+    }
+
+    /// `X-Proxy-Retries` value: the failovers before this response, when exposed.
+    fn retry_header(&self, ctx: &RequestContext) -> Option<String> {
+        self.expose_retry_header
+            .then(|| ctx.upstream_attempts.saturating_sub(1).to_string())
+    }
+
+    /// Whether a TTFB is over `SLOW_TTFB_MS`, which is never the case while it is unset.
+    fn is_slow_ttfb(&self, ttfb: Duration) -> bool {
+        self.slow_ttfb_threshold
+            .is_some_and(|threshold| ttfb > threshold)
+    }
+
+    /// Records on `ctx` whether the attempt failed without a single response byte, by the
+    /// upstream closing early or staying silent until the read timeout, and returns whether
+    /// to retry it on another IP. Only idempotent requests with an intact retry buffer fail
+    /// over, and each egress IP gets at most one attempt.
+    fn classify_no_response(
+        &self,
+        e: &Error,
+        method: &Method,
+        retry_buffer_intact: bool,
+        ctx: &mut RequestContext,
+    ) -> bool {
+        let no_response = e.esource() == &ErrorSource::Upstream && ctx.ttfb.is_none();
+        ctx.upstream_closed_early = no_response && e.etype() == &ConnectionClosed;
+        ctx.upstream_silent = no_response && e.etype() == &ReadTimedout;
+
+        let can_fail_over = ctx.excluded_ips.len() + 1 < self.ip_addresses.len()
+            && retry_buffer_intact
+            && method.is_idempotent();
+        can_fail_over
+            && ((ctx.upstream_closed_early && self.retry_on_upstream_close)
+                || (ctx.upstream_silent && self.retry_on_upstream_silent))
+    }
+
+    /// Exits the process once every egress IP has been quarantined continuously for `limit`,
+    /// so an orchestrator can reschedule the proxy instead of it serving errors indefinitely.
+    fn spawn_exit_if_all_down(&self, limit: Duration) {
+        let Some(burn_detector) = self.burn_detector.clone() else {
+            return;
+        };
+        let mut watch = AllDownWatch::new(self.ip_addresses.clone(), limit);
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(1));
+            if watch.limit_exceeded(&burn_detector, Instant::now()) {
+                error!(
+                    "All egress IPs quarantined for {}s, exiting (EXIT_IF_ALL_DOWN_SECS)",
+                    limit.as_secs()
+                );
+                std::process::exit(1);
+            }
+        });
+    }
+
+    fn admin_app(&self) -> AdminApp {
+        AdminApp {
+            ip_addresses: self.ip_addresses.clone(),
+            egress_stats: self.egress_stats.clone(),
+            burn_detector: self.burn_detector.clone(),
+            live_interval: self.admin_live_interval,
+        }
+    }
+
+    /// Request ID header name and value to put on responses the proxy generates itself.
+    fn request_id_tag<'a>(&'a self, ctx: &'a RequestContext) -> Option<(&'a str, &'a str)> {
+        self.request_id_header
+            .as_deref()
+            .zip(ctx.request_id.as_deref())
+    }
+
+    /// Keeps a correlation ID supplied by the client and only mints one when it is missing.
+    /// The flag is true when the client's ID was kept.
+    fn resolve_request_id(&self, request: &RequestHeader) -> (String, bool) {
+        match self
+            .request_id_header
+            .as_deref()
+            .and_then(|header_name| request.headers.get(header_name))
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(request_id) => (request_id.to_string(), true),
+            None => (self.generate_request_id(), false),
+        }
+    }
+
+    fn generate_request_id(&self) -> String {
+        let sequence = self.request_id_counter.fetch_add(1, Ordering::Relaxed);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_micros())
+            .unwrap_or_default();
+        format!("{:x}-{:x}", timestamp, sequence)
+    }
+
+    /// Runs a synthetic request through credential checking and IP selection, and checks that
+    /// every egress IP can be bound, returning the first problem found. Peers are only bound
+    /// to their egress IP with `FIXED_SOURCE_PORT`, so without it the bind check only catches
+    /// pool IPs that are missing from this host.
+    fn self_test(&self, username: &str, password: &str) -> Result<(), String> {
+        if username.is_empty() || password.is_empty() {
+            return Err("credentials are empty".into());
+        }
+        if username.contains(':') {
+            return Err("username contains ':' and can never authenticate".into());
+        }
+
+        let mut request = RequestHeader::build("GET", b"/", None)
+            .map_err(|e| format!("could not build test request: {}", e))?;
+        let valid = Self::create_basic_auth_header(username, password);
+        let invalid = Self::create_basic_auth_header(username, &format!("{}-wrong", password));
+
+        for (header, should_pass) in [(valid, true), (invalid, false)] {
+            request
+                .insert_header("Proxy-Authorization", header)
+                .map_err(|e| format!("could not build test credentials: {}", e))?;
+            let header = request
+                .headers
+                .get("Proxy-Authorization")
+                .and_then(|value| value.to_str().ok());
+
+            match (self.check_credentials(header), should_pass) {
+                (AuthResult::Authenticated { username: name }, true) if name == username => {}
+                (AuthResult::Unauthenticated { .. }, false) => {}
+                (_, true) => return Err("configured credentials were rejected".into()),
+                (_, false) => return Err("wrong credentials were accepted".into()),
+            }
+        }
+
+        let ip = self.select_next_ip(&request, 0, &[]);
+        if !self.ip_addresses.iter().any(|pool_ip| pool_ip == ip) {
+            return Err(format!("selected egress IP '{}' is not in the pool", ip));
+        }
+
+        for ip in &self.ip_addresses {
+            let Ok(address) = ip.parse::<IpAddr>() else {
+                return Err(format!("egress IP '{}' is not a valid IP address", ip));
+            };
+            if !is_local_address(address) {
+                return Err(format!("egress IP {} cannot be bound on this host", ip));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a raw `Proxy-Authorization` header value without needing a `Session`.
+    pub fn check_credentials(&self, header: Option<&str>) -> AuthResult {
+        let reason = match header {
+            Some(header) if header == self.expected_auth_header => {
+                return AuthResult::Authenticated {
+                    username: self.username.clone(),
+                }
+            }
+            None => AuthFailure::Missing,
+            Some(header) if header.trim().is_empty() => AuthFailure::Empty,
+            Some(_) => AuthFailure::Invalid,
+        };
+        AuthResult::Unauthenticated { reason }
+    }
+}
+
+// ============================================================================
+// Request Context
+// ============================================================================
+
+/// Per-request state carried across the proxy phases.
+#[derive(Default)]
+pub struct RequestContext {
+    source_ip: Option<String>,
+    /// Principal holding a concurrency slot, released in `logging`.
+    principal: Option<String>,
+    /// Upstream attempts made so far, including retries on another IP.
+    upstream_attempts: usize,
+    /// Holds the egress IP's `FIXED_SOURCE_PORT` until the request is done.
+    source_port_permit: Option<OwnedSemaphorePermit>,
+    /// Pool IPs not to use: those the client excluded via `X-Proxy-Exclude-IP`, plus those
+    /// an earlier attempt already failed on. At least one IP is always left out of it.
+    excluded_ips: Vec<String>,
+    request_id: Option<String>,
+    peer_selected_at: Option<Instant>,
+    upstream_connected_at: Option<Instant>,
+    request_sent_at: Option<Instant>,
+    connect_duration: Option<Duration>,
+    upstream_duration: Option<Duration>,
+    /// Time from sending the request header upstream to receiving the response header.
+    ttfb: Option<Duration>,
+    /// Upstream response had neither Content-Length nor Transfer-Encoding.
+    close_delimited: bool,
+    /// When the client must have finished sending the request body (`CLIENT_BODY_TIMEOUT_MS`).
+    client_body_deadline: Option<Instant>,
+    /// A copy of this request was already sent to the mirror (retries are not mirrored again).
+    mirrored: bool,
+    /// The last attempt was abandoned only to retire a pooled connection past its maximum age.
+    retired_connection: bool,
+    /// The last upstream attempt closed the connection before sending any response bytes.
+    upstream_closed_early: bool,
+    /// The last upstream attempt hit the read timeout before sending any response bytes.
+    upstream_silent: bool,
+    /// Original upstream status when a 5xx was replaced by the canonical proxy error.
+    normalized_upstream_status: Option<u16>,
+}
+
+impl RequestContext {
+    /// Whether the client is still sending the request body past its deadline. The chunk
+    /// that completes the body is let through even when it arrives late.
+    fn client_body_overdue(&self, end_of_stream: bool, now: Instant) -> bool {
+        !end_of_stream
+            && self
+                .client_body_deadline
+                .is_some_and(|deadline| now >= deadline)
+    }
+
+    fn server_timing_header(&self) -> Option<String> {
+        let metrics: Vec<String> = [
+            ("connect", self.connect_duration),
+            ("upstream", self.upstream_duration),
+            ("ttfb", self.ttfb),
+        ]
+        .iter()
+        .filter_map(|(name, duration)| {
+            duration.map(|d| format!("{};dur={:.3}", name, d.as_secs_f64() * 1000.0))
+        })
+        .collect();
+
+        if metrics.is_empty() {
+            None
+        } else {
+            Some(metrics.join(", "))
+        }
+    }
+}
+
+// ============================================================================
+// HTTP Proxy Implementation
+// ============================================================================
+
+#[async_trait]
+impl ProxyHttp for MultiIPProxy {
+    type CTX = RequestContext;
+
+    fn new_ctx(&self) -> Self::CTX {
+        RequestContext::default()
+    }
+
+    fn init_downstream_modules(&self, modules: &mut HttpModules) {
+        // The module has to be enabled up front to record Accept-Encoding; request_filter then
+        // switches it off and response_filter back on per response.
+        let level = if self.compression.is_some() {
+            COMPRESSION_LEVELS[0].1
+        } else {
+            0
+        };
+        modules.add_module(ResponseCompressionBuilder::enable(level));
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        ctx.peer_selected_at = Some(Instant::now());
+        // Describes the previous attempt, which error_while_proxy has already acted on
+        ctx.upstream_closed_early = false;
+        ctx.upstream_silent = false;
+
+        if let Some(previous_ip) = ctx.source_ip.take() {
+            if std::mem::take(&mut ctx.retired_connection) {
+                // Only the pooled connection was too old, which says nothing about the IP and
+                // does not count as a retry
+                self.egress_stats.request_cancelled(&previous_ip);
+                ctx.upstream_attempts = ctx.upstream_attempts.saturating_sub(1);
+            } else {
+                // Retrying on another IP: the previous attempt ended without a response
+                self.egress_stats.request_finished(&previous_ip, 0, None);
+                if ctx.excluded_ips.len() + 1 < self.ip_addresses.len()
+                    && !ctx.excluded_ips.contains(&previous_ip)
+                {
+                    ctx.excluded_ips.push(previous_ip.clone());
+                }
+            }
+            self.record_active_requests(&previous_ip);
+        }
+        // The previous attempt's connection is gone, so its port is free again
+        ctx.source_port_permit = None;
+
+        let source_ip = self.select_next_ip(
+            session.req_header(),
+            ctx.upstream_attempts,
+            &ctx.excluded_ips,
+        );
+        let target_info = extract_target_info(session);
+        let resolved_address = self.resolve_override(source_ip, &target_info.host);
+        let mut peer = create_http_peer(&target_info, resolved_address)?;
+
+        // Origin-form targets are routed from the Host header, so the management listeners
+        // are guarded on the address it resolved to, before any connection is made
+        if let Some(address) = peer.address().as_inet() {
+            if self.is_management_address(*address) {
+                warn!("Blocked proxy request to an admin/metrics endpoint");
+                return Error::e_explain(HTTPStatus(403), "admin/metrics endpoint target");
+            }
+        }
+
+        if self.enforce_sni_host_match
+            && peer.tls()
+            && !sni_matches_host(&peer.sni, session.req_header())
+        {
+            warn!(
+                "Rejecting request: SNI {} does not match Host header",
+                peer.sni
+            );
+            return Error::e_explain(HTTPStatus(403), "SNI does not match Host");
+        }
+
+        if let Some(fixed) = &self.fixed_source_port {
+            let Some(permit) = fixed.acquire(source_ip).await else {
+                warn!(
+                    "Fixed source port on IP {} stayed busy, giving up",
+                    source_ip
+                );
+                return Error::e_explain(HTTPStatus(503), "fixed source port busy");
+            };
+            ctx.source_port_permit = Some(permit);
+        }
+        ctx.upstream_attempts += 1;
+        self.egress_stats.request_started(source_ip);
+        self.record_active_requests(source_ip);
+        ctx.source_ip = Some(source_ip.to_string());
+
+        debug!(
+            "Routing request to {}:{} via IP {}",
+            target_info.host, target_info.port, source_ip
+        );
+        if let Some(address) = resolved_address {
+            debug!(
+                "Resolved {} to {} for IP {}",
+                target_info.host, address, source_ip
+            );
+        }
+
+        let timeouts = self.timeouts_for(&target_info.host);
+        peer.options.connection_timeout = timeouts.connect;
+        peer.options.read_timeout = timeouts.read;
+        peer.options.write_timeout = timeouts.write;
+        if self.is_no_keepalive_host(&target_info.host) {
+            // Expire the connection as soon as it would be pooled, so it is never reused
+            peer.options.idle_timeout = Some(Duration::ZERO);
+        }
+        if self.require_e2e_h2 && session.is_http2() {
+            // Only offer h2 upstream; an HTTP/1.1-only origin then fails the attempt
+            // instead of being silently downgraded
+            peer.options.alpn = ALPN::H2;
+        }
+        if let Some(fixed) = &self.fixed_source_port {
+            fixed.apply(&mut peer, source_ip);
+        }
+        Ok(Box::new(peer))
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        if self.compression.is_some() {
+            // Accept-Encoding is recorded by now. Responses generated here are sent as is,
+            // since they never pass through the size and content-type checks
+            set_response_compression(session, false);
+        }
+
+        if self.request_id_header.is_some() {
+            // Resolved once up front so retries reuse it and early rejections are logged with it
+            let (request_id, preserved) = self.resolve_request_id(session.req_header());
+            if !preserved {
+                debug!("Generated request ID {}", request_id);
+            }
+            ctx.request_id = Some(request_id);
+        }
+
+        if let Some(maintenance) = &self.maintenance {
+            send_maintenance_response(session, maintenance, self.request_id_tag(ctx)).await?;
+            return Ok(true);
+        }
+
+        // Both framings at once is the classic request smuggling setup, so it is always refused
+        // and the connection is not reused, since its framing can no longer be trusted
+        if has_conflicting_framing(session) {
+            warn!("Rejecting request with both Content-Length and Transfer-Encoding");
+            session.set_keepalive(None);
+            send_error_response(
+                session,
+                400,
+                "Bad Request: conflicting message framing",
+                self.request_id_tag(ctx),
+            )
+            .await?;
+            return Ok(true);
+        }
+
+        let username = match self.check_credentials(extract_auth_header(session)) {
+            AuthResult::Authenticated { username } => username,
+            AuthResult::Unauthenticated { reason } => {
+                warn!(
+                    "Unauthorized access attempt ({} credentials)",
+                    reason.as_str()
+                );
+                self.metrics
+                    .incr_counter("proxy_auth_failures_total", &[("reason", reason.as_str())]);
+                send_auth_required_response(session, self.request_id_tag(ctx)).await?;
+                return Ok(true); // Stop request processing
+            }
+        };
+        debug!("Authenticated as {}", username);
+
+        if self.rejects_missing_host(session.req_header()) {
+            warn!("Rejecting HTTP/1.1 request without Host header or absolute URI");
+            send_error_response(
+                session,
+                400,
+                "Bad Request: missing Host header",
+                self.request_id_tag(ctx),
+            )
+            .await?;
+            return Ok(true);
+        }
+
+        if request_target_has_fragment(session) {
+            // Already dropped while parsing the URI, so only the client's mistake is logged
+            self.log_uri_fragment("request target");
+        }
+
+        if self.allow_exclude_ip_header {
+            ctx.excluded_ips = self.excluded_ips(session.req_header());
+            if ctx.excluded_ips.len() == self.ip_addresses.len() {
+                warn!("Rejecting request: X-Proxy-Exclude-IP excludes the whole IP pool");
+                send_error_response(
+                    session,
+                    503,
+                    "Service Unavailable: all egress IPs are excluded",
+                    self.request_id_tag(ctx),
+                )
+                .await?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(limiter) = &self.concurrency_limiter {
+            if !limiter.try_acquire(&username) {
+                warn!("Concurrency limit reached for {}", username);
+                self.metrics
+                    .incr_counter("proxy_concurrency_rejections_total", &[]);
+                send_error_response(session, 429, "Too Many Requests", self.request_id_tag(ctx))
+                    .await?;
+                return Ok(true);
+            }
+            ctx.principal = Some(username);
+        }
+
+        if let Some(timeout) = self.client_body_timeout {
+            // The per-read timeout catches a stalled body, the deadline a trickling one
+            ctx.client_body_deadline = Some(Instant::now() + timeout);
+            session.set_read_timeout(Some(timeout));
+        }
+
+        Ok(false) // Allow request to proceed
+    }
+
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if ctx.client_body_overdue(end_of_stream, Instant::now()) {
+            warn!("Client did not finish sending the request body in time");
+            return Err(Error::create(
+                ReadTimedout,
+                ErrorSource::Downstream,
+                Some("client body timeout".into()),
+                None,
+            ));
+        }
+        Ok(())
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let host_without_fragment = upstream_request
+            .headers
+            .get("Host")
+            .and_then(|host| strip_fragment(host.as_bytes()))
+            .map(<[u8]>::to_vec);
+        if let Some(host) = host_without_fragment {
+            self.log_uri_fragment("Host header");
+            upstream_request.insert_header("Host", host)?;
+        }
+
+        if self.fixed_source_port.is_some()
+            || self.is_no_keepalive_host(&extract_target_info(session).host)
+        {
+            upstream_request.insert_header("Connection", "close")?;
+        }
+
+        if self.forward_client_info {
+            let client_ip = session
+                .client_addr()
+                .and_then(|address| address.as_inet())
+                .map(|address| address.ip());
+            let forwarded_for = normalize_forwarded_for(
+                upstream_request,
+                client_ip,
+                self.forwarded_for_max_entries,
+            );
+            upstream_request.remove_header("X-Forwarded-For");
+            if !forwarded_for.is_empty() {
+                upstream_request.insert_header("X-Forwarded-For", forwarded_for)?;
+            }
+        }
+
+        if self.allow_exclude_ip_header {
+            // Selection hint for this proxy only, never meant for the origin
+            upstream_request.remove_header("X-Proxy-Exclude-IP");
+        }
+
+        if let Some((header_name, request_id)) = self.request_id_tag(ctx) {
+            upstream_request.insert_header(header_name.to_string(), request_id)?;
+        }
+
+        if let Some(mirror) = &self.mirror {
+            let has_body = upstream_request.headers.contains_key("Content-Length")
+                || upstream_request.headers.contains_key("Transfer-Encoding");
+            if !ctx.mirrored && !has_body && mirror.should_mirror() {
+                mirror.spawn(upstream_request);
+            }
+            ctx.mirrored = true;
+        }
+
+        ctx.request_sent_at = Some(Instant::now());
+        Ok(())
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let connection_age = digest
+            .and_then(|digest| digest.timing_digest.iter().flatten().next())
+            .and_then(|timing| timing.established_ts.elapsed().ok());
+        if let Some(age) = connection_age.filter(|age| self.retires_connection(reused, *age)) {
+            // Failing here drops the stale connection; the retry gets a fresh one
+            debug!(
+                "Retiring {}s old pooled connection to {}",
+                age.as_secs(),
+                peer
+            );
+            ctx.retired_connection = true;
+            let mut e = Error::create(
+                ErrorType::new("ConnectionTooOld"),
+                ErrorSource::Upstream,
+                Some("pooled connection exceeded MAX_CONN_AGE_SECS".into()),
+                None,
+            );
+            e.set_retry(true);
+            return Err(e);
+        }
+
+        let now = Instant::now();
+        ctx.connect_duration = ctx.peer_selected_at.map(|selected| now - selected);
+        ctx.upstream_connected_at = Some(now);
+        Ok(())
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        ctx.close_delimited = !upstream_response.headers.contains_key("Content-Length")
+            && !upstream_response.headers.contains_key("Transfer-Encoding");
+        ctx.upstream_duration = ctx
+            .upstream_connected_at
+            .map(|connected| connected.elapsed());
+        ctx.ttfb = ctx.request_sent_at.map(|sent| sent.elapsed());
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        // Pingora re-frames close-delimited bodies as chunked so the client connection can be
+        // kept alive. With re-chunking disabled, HTTP/1.1 clients get the body delimited by
+        // closing the connection instead. HTTP/1.0 requests are left alone: pingora reads their
+        // request body until close, so closing to end the response would deadlock.
+        if ctx.close_delimited
+            && !self.rechunk_close_delimited
+            && session.req_header().version == Version::HTTP_11
+            && upstream_response
+                .remove_header("Transfer-Encoding")
+                .is_some()
+        {
+            debug!("Relaying close-delimited upstream body without re-chunking");
+            session.set_keepalive(None);
+        }
+
+        self.normalize_upstream_error(upstream_response, ctx)?;
+
+        if self.compression.is_some() {
+            let compress = self.should_compress(session.req_header(), upstream_response);
+            set_response_compression(session, compress);
+        }
+
+        // Announced trailers that cannot be relayed would leave the client waiting for fields
+        // that never come
+        if !self.relays_trailers(session, upstream_response)
+            && upstream_response.remove_header("Trailer").is_some()
+        {
+            debug!("Stripped Trailer header, trailers cannot be relayed to this client");
+        }
+
+        if let Some(retries) = self.retry_header(ctx) {
+            upstream_response.insert_header("X-Proxy-Retries", retries)?;
+        }
+
+        if self.expose_server_timing {
+            if let Some(server_timing) = ctx.server_timing_header() {
+                // Appended so metrics reported by the upstream itself are kept
+                upstream_response.append_header("Server-Timing", server_timing)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        if drops_response_body(session.req_header()) {
+            if body.take().is_some() {
+                debug!("Dropped upstream body sent in response to HEAD");
+            }
+            return Ok(None);
+        }
+
+        if ctx.normalized_upstream_status.is_some() {
+            // Discard the upstream body and emit the canonical one once it is complete
+            *body = end_of_stream.then(|| Bytes::from_static(UPSTREAM_ERROR_BODY));
+        }
+        Ok(None)
+    }
+
+    async fn response_trailer_filter(
+        &self,
+        session: &mut Session,
+        upstream_trailers: &mut HeaderMap,
+        _ctx: &mut Self::CTX,
+    ) -> Result<Option<Bytes>> {
+        if !self.forward_trailers || !session.is_http2() || !accepts_trailers(session.req_header())
+        {
+            if !upstream_trailers.is_empty() {
+                debug!("Stripped {} response trailers", upstream_trailers.len());
+            }
+            upstream_trailers.clear();
+        }
+        Ok(None)
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        let retry_buffer_intact = !session.as_ref().retry_buffer_truncated();
+        e.retry.decide_reuse(client_reused && retry_buffer_intact);
+
+        let retry =
+            self.classify_no_response(&e, &session.req_header().method, retry_buffer_intact, ctx);
+        if ctx.upstream_closed_early {
+            warn!("Upstream {} closed without response", peer);
+        }
+        if ctx.upstream_silent {
+            warn!("Upstream {} sent nothing before the read timeout", peer);
+        }
+        if retry {
+            e.set_retry(true);
+        }
+        self.enforce_retry_budget(session, &mut e, ctx);
+        e
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        self.enforce_retry_budget(session, &mut e, ctx);
+        e
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy {
+        let Some((code, message)) = proxy_failure_status(e, ctx) else {
+            return respond_proxy_failure(session, e, self.request_id_tag(ctx)).await;
+        };
+
+        if let Err(e) = send_error_response(session, code, message, self.request_id_tag(ctx)).await
+        {
+            warn!("Failed to send error response to downstream: {}", e);
+        }
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
+    async fn logging(&self, session: &mut Session, _error: Option<&Error>, ctx: &mut Self::CTX) {
+        if let Some((limiter, principal)) =
+            self.concurrency_limiter.as_ref().zip(ctx.principal.take())
+        {
+            limiter.release(&principal);
+        }
+
+        let status_code = get_response_status(session);
+        let method = &session.req_header().method;
+        let uri = &session.req_header().uri;
+
+        match &ctx.request_id {
+            Some(request_id) => info!("[{}] {} {} -> {}", request_id, method, uri, status_code),
+            None => info!("{} {} -> {}", method, uri, status_code),
+        }
+
+        if let Some(source_ip) = &ctx.source_ip {
+            self.record_outcome(source_ip, status_code, ctx);
+
+            if let Some(ttfb) = ctx.ttfb {
+                let ttfb_ms = ttfb.as_secs_f64() * 1000.0;
+                if self.is_slow_ttfb(ttfb) {
+                    warn!(
+                        "Slow upstream TTFB {:.1}ms for {} {} via IP {}",
+                        ttfb_ms, method, uri, source_ip
+                    );
+                } else {
+                    debug!("Upstream TTFB {:.1}ms via IP {}", ttfb_ms, source_ip);
+                }
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+struct TargetInfo {
+    host: String,
+    port: u16,
+    use_tls: bool,
+}
+
+fn extract_target_info(session: &Session) -> TargetInfo {
+    let request = session.req_header();
+    let uri = &request.uri;
+
+    // Absolute-form targets carry their authority, origin-form requests rely on Host
+    let authority = uri.authority().cloned().or_else(|| {
+        request
+            .headers
+            .get("Host")
+            .map(|value| strip_fragment(value.as_bytes()).unwrap_or(value.as_bytes()))
+            .and_then(|value| Authority::try_from(value).ok())
+    });
+
+    let host = authority
+        .as_ref()
+        .map(|a| a.host())
+        .unwrap_or("localhost")
+        .to_string();
+
+    let use_tls = uri.scheme_str() == Some("https");
+    let default_port = if use_tls { 443 } else { 80 };
+    let port = authority
+        .as_ref()
+        .and_then(|a| a.port_u16())
+        .unwrap_or(default_port);
+
+    TargetInfo {
+        host,
+        port,
+        use_tls,
+    }
+}
+
+/// Guards against domain fronting: the SNI, taken from the URI authority (`:authority` for
+/// h2), must name the host the origin reads from `Host`, or from the authority itself when
+/// there is no `Host` header. Fails closed when neither names a host.
+fn sni_matches_host(sni: &str, request: &RequestHeader) -> bool {
+    let host = match request.headers.get("Host") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<Authority>().ok()),
+        None => request.uri.authority().cloned(),
+    };
+
+    host.is_some_and(|authority| authority.host().eq_ignore_ascii_case(sni))
+}
+
+/// Returns the value without its `#fragment`, or `None` when there is no fragment. Fragments
+/// are client-side only and must never reach the upstream.
+fn strip_fragment(value: &[u8]) -> Option<&[u8]> {
+    value
+        .iter()
+        .position(|byte| *byte == b'#')
+        .map(|index| &value[..index])
+}
+
+/// True when the HTTP/1.x request line's target carried a `#fragment`. The http crate drops
+/// fragments while parsing the URI, so only the raw header still shows one.
+fn request_target_has_fragment(session: &Session) -> bool {
+    let ServerSession::H1(downstream) = session.as_downstream() else {
+        return false;
+    };
+    raw_target_has_fragment(&downstream.get_headers_raw_bytes())
+}
+
+fn raw_target_has_fragment(raw_header: &[u8]) -> bool {
+    raw_header
+        .split(|byte| *byte == b'\n')
+        .next()
+        .and_then(|request_line| request_line.split(|byte| *byte == b' ').nth(1))
+        .is_some_and(|target| target.contains(&b'#'))
+}
+
+/// Matches a lowercase host against an exact host or a `*.suffix` pattern.
+fn host_matches_pattern(host: &str, pattern: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .is_some_and(|prefix| prefix.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// Binding only succeeds for addresses assigned to one of this host's interfaces.
+fn is_local_address(ip: IpAddr) -> bool {
+    UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+/// Never relay a body to HEAD, even if the upstream sends one. Content-Length is left
+/// untouched since it describes the equivalent GET response.
+fn drops_response_body(request: &RequestHeader) -> bool {
+    request.method == Method::HEAD
+}
+
+/// Status and message for failures answered apart from pingora's generic error response.
+fn proxy_failure_status(e: &Error, ctx: &RequestContext) -> Option<(u16, &'static str)> {
+    let client_body_timed_out = ctx.client_body_deadline.is_some()
+        && e.etype() == &ReadTimedout
+        && e.esource() == &ErrorSource::Downstream;
+
+    if ctx.upstream_closed_early {
+        Some((502, "Bad Gateway: upstream closed without response"))
+    } else if ctx.upstream_silent {
+        Some((504, "Gateway Timeout: upstream silent"))
+    } else if client_body_timed_out {
+        Some((408, "Request Timeout: request body not received in time"))
+    } else {
+        None
+    }
+}
+
+/// Resolves the target up front: `HttpPeer::new` would panic on a name that does not resolve.
+fn create_http_peer(target: &TargetInfo, resolved_address: Option<IpAddr>) -> Result<HttpPeer> {
+    let address = match resolved_address {
+        Some(ip) => SocketAddr::new(ip, target.port),
+        None => format!("{}:{}", target.host, target.port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or_else(|| {
+                warn!("Could not resolve upstream host {}", target.host);
+                Error::explain(HTTPStatus(502), "upstream host did not resolve")
+            })?,
+    };
+    Ok(HttpPeer::new(address, target.use_tls, target.host.clone()))
+}
+
+fn extract_auth_header(session: &Session) -> Option<&str> {
+    session
+        .req_header()
+        .headers
+        .get("Proxy-Authorization")
+        .and_then(|value| value.to_str().ok())
+}
+
+async fn send_auth_required_response(
+    session: &mut Session,
+    request_id: Option<(&str, &str)>,
+) -> Result<()> {
+    const BODY: &[u8] = b"Proxy Authentication Required";
+
+    let mut response = ResponseHeader::build(407, None)?;
+    response.insert_header("Proxy-Authenticate", "Basic realm=\"Proxy\"")?;
+    response.insert_header("Content-Type", "text/plain")?;
+    response.insert_header("Content-Length", BODY.len().to_string())?;
+    insert_request_id(&mut response, request_id)?;
+
+    session
+        .write_response_header(Box::new(response), false)
+        .await?;
+    session.write_response_body(Some(BODY.into()), true).await?;
+
+    Ok(())
+}
+
+const UPSTREAM_ERROR_BODY: &[u8] = b"Bad Gateway: upstream server error";
+
+fn build_upstream_error_response(
+    upstream_status: u16,
+    request_id: Option<(&str, &str)>,
+) -> Result<ResponseHeader> {
+    let mut response = ResponseHeader::build(502, None)?;
+    response.insert_header("Content-Type", "text/plain")?;
+    response.insert_header("Content-Length", UPSTREAM_ERROR_BODY.len().to_string())?;
+    response.insert_header("X-Proxy-Upstream-Status", upstream_status.to_string())?;
+    insert_request_id(&mut response, request_id)?;
+    Ok(response)
+}
+
+/// Echoes the request ID on a response the proxy generated, so clients can quote it.
+fn insert_request_id(
+    response: &mut ResponseHeader,
+    request_id: Option<(&str, &str)>,
+) -> Result<()> {
+    if let Some((header_name, request_id)) = request_id {
+        response.insert_header(header_name.to_string(), request_id)?;
+    }
+    Ok(())
+}
+
+async fn send_error_response(
+    session: &mut Session,
+    status: u16,
+    message: &str,
+    request_id: Option<(&str, &str)>,
+) -> Result<()> {
+    let mut response = ResponseHeader::build(status, None)?;
+    response.insert_header("Content-Type", "text/plain")?;
+    response.insert_header("Content-Length", message.len().to_string())?;
+    insert_request_id(&mut response, request_id)?;
+
+    session
+        .write_response_header(Box::new(response), false)
+        .await?;
+    session
+        .write_response_body(Some(Bytes::copy_from_slice(message.as_bytes())), true)
+        .await?;
+
+    Ok(())
+}
+
+/// Pingora's default `fail_to_proxy` behaviour: pick a status from the error and respond.
+async fn respond_proxy_failure(
+    session: &mut Session,
+    e: &Error,
+    request_id: Option<(&str, &str)>,
+) -> FailToProxy {
+    let code = match (e.etype(), e.esource()) {
+        (HTTPStatus(code), _) => *code,
+        (_, ErrorSource::Upstream) => 502,
+        // The downstream connection is already dead
+        (WriteError | ReadError | ConnectionClosed, ErrorSource::Downstream) => 0,
+        (_, ErrorSource::Downstream) => 400,
+        (_, ErrorSource::Internal | ErrorSource::Unset) => 500,
+    };
+
+    if code > 0 {
+        let mut response = ServerSession::generate_error(code);
+        let written = match insert_request_id(&mut response, request_id) {
+            Ok(()) => session.write_error_response(response, Bytes::new()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!("Failed to send error response to downstream: {}", e);
+        }
+    }
+
+    FailToProxy {
+        error_code: code,
+        can_reuse_downstream: false,
+    }
+}
+
+fn build_maintenance_response(
+    maintenance: &MaintenanceResponse,
+    request_id: Option<(&str, &str)>,
+) -> Result<(ResponseHeader, Bytes)> {
+    let mut response = ResponseHeader::build(maintenance.status, None)?;
+    response.insert_header("Content-Type", "text/plain")?;
+    response.insert_header("Content-Length", maintenance.body.len().to_string())?;
+    if let Some(location) = &maintenance.location {
+        response.insert_header("Location", location)?;
+    }
+    insert_request_id(&mut response, request_id)?;
+    Ok((response, Bytes::from(maintenance.body.clone())))
+}
+
+async fn send_maintenance_response(
+    session: &mut Session,
+    maintenance: &MaintenanceResponse,
+    request_id: Option<(&str, &str)>,
+) -> Result<()> {
+    let (response, body) = build_maintenance_response(maintenance, request_id)?;
+    session
+        .write_response_header(Box::new(response), false)
+        .await?;
+    session.write_response_body(Some(body), true).await?;
+
+    Ok(())
+}
+
+/// Switches the downstream compression module on for every supported encoding, or off.
+fn set_response_compression(session: &mut Session, enabled: bool) {
+    let Some(compression) = session
+        .downstream_modules_ctx
+        .get_mut::<ResponseCompression>()
+    else {
+        return;
+    };
+
+    compression.adjust_level(0);
+    if enabled {
+        for (algorithm, level) in COMPRESSION_LEVELS {
+            compression.adjust_algorithm_level(algorithm, level);
+        }
+    }
+}
+
+/// Clients opt into trailers with `TE: trailers`.
+fn accepts_trailers(request: &RequestHeader) -> bool {
+    request
+        .headers
+        .get_all("TE")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| {
+            token
+                .split(';')
+                .next()
+                .is_some_and(|name| name.trim().eq_ignore_ascii_case("trailers"))
+        })
+}
+
+/// Rebuilds the X-Forwarded-For chain with the client appended as the last hop. Entries that
+/// are not IP addresses are dropped, ports and brackets are removed, and a repeated address
+/// keeps only its last position so the hop we append stays rightmost. `max_entries` keeps
+/// the most recent hops.
+fn normalize_forwarded_for(
+    request: &RequestHeader,
+    client_ip: Option<IpAddr>,
+    max_entries: Option<usize>,
+) -> String {
+    let mut chain: Vec<IpAddr> = request
+        .headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| {
+            let entry = entry.trim().trim_matches('"');
+            entry
+                .parse::<IpAddr>()
+                .or_else(|_| entry.parse::<SocketAddr>().map(|address| address.ip()))
+                .ok()
+        })
+        .chain(client_ip)
+        .map(|ip| ip.to_canonical())
+        .collect();
+
+    let mut seen = HashSet::new();
+    chain.reverse();
+    chain.retain(|ip| seen.insert(*ip));
+    if let Some(max_entries) = max_entries {
+        chain.truncate(max_entries);
+    }
+    chain.reverse();
+
+    chain
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// True when an HTTP/1.x request carried both Content-Length and Transfer-Encoding. Pingora
+/// drops Content-Length from the parsed header in that case, so the raw header is scanned.
+fn has_conflicting_framing(session: &Session) -> bool {
+    let ServerSession::H1(downstream) = session.as_downstream() else {
+        return false;
+    };
+    has_both_framing_headers(&downstream.get_headers_raw_bytes())
+}
+
+fn has_both_framing_headers(raw_header: &[u8]) -> bool {
+    let header_names: Vec<&[u8]> = raw_header
+        .split(|byte| *byte == b'\n')
+        .skip(1) // request line
+        .filter_map(|line| line.split(|byte| *byte == b':').next())
+        .map(<[u8]>::trim_ascii)
+        .collect();
+    let has_header = |name: &[u8]| {
+        header_names
+            .iter()
+            .any(|header_name| header_name.eq_ignore_ascii_case(name))
+    };
+
+    has_header(b"Content-Length") && has_header(b"Transfer-Encoding")
+}
+
+/// HTTP/1.1 requires a `Host` header unless the target is in absolute form. An empty one
+/// names no destination to route to, so it counts as missing.
+fn is_missing_host(request: &RequestHeader) -> bool {
+    request.version == Version::HTTP_11
+        && request.uri.authority().is_none()
+        && request
+            .headers
+            .get("Host")
+            .is_none_or(|host| host.as_bytes().trim_ascii().is_empty())
+}
+
+fn get_response_status(session: &Session) -> u16 {
+    session
+        .response_written()
+        .map(|response| response.status.as_u16())
+        .unwrap_or(0)
+}
+
+// ============================================================================
+// Application Entry Point
+// ============================================================================
+
+fn main() {
+    initialize_logger();
+
+    let config = ProxyConfig::load_from_environment()
+        .and_then(|config| config.validate().map(|_| config))
+        .unwrap_or_else(|error_message| {
+            eprintln!("Configuration Error: {}", error_message);
+            std::process::exit(1);
+        });
+
+    log_startup_info(&config);
+
+    let server = start_proxy_server(config);
+    server.run_forever();
+}
+
+fn initialize_logger() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+}
+
+fn log_startup_info(config: &ProxyConfig) {
+    info!("Starting Pingora Multi-IP Proxy");
+    info!("Listen address: {}", config.listen_address);
+    info!("IP pool size: {}", config.active_ip_addresses().len());
+    if !config.reserve_ip_addresses().is_empty() {
+        info!(
+            "Reserve IPs: {} (held back by POOL_ACTIVE_LIMIT)",
+            config.reserve_ip_addresses().len()
+        );
+    }
+    info!("Authentication: enabled");
+}
+
+/// Probes an admin/metrics listen address before its service is added. With
+/// `ADMIN_BIND_FAILURE=warn` an unavailable address disables that endpoint instead of
+/// aborting startup.
+fn listener_available(name: &str, address: SocketAddr, on_failure: FailureMode) -> bool {
+    let Err(e) = std::net::TcpListener::bind(address) else {
+        return true;
+    };
+
+    match on_failure {
+        FailureMode::Warn => {
+            warn!("{} endpoint disabled: cannot bind {}: {}", name, address, e);
+            false
+        }
+        FailureMode::Fatal => {
+            error!("{} endpoint cannot bind {}: {}", name, address, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn start_proxy_server(config: ProxyConfig) -> Server {
+    let mut server = Server::new(Some(Opt::default())).expect("Failed to create server");
+
+    server.bootstrap();
+
+    let listen_address = config.listen_address.clone();
+    let accept_h2c = config.accept_h2c;
+    let admin_bind_failure = config.admin_bind_failure;
+    let exit_if_all_down = config.exit_if_all_down;
+    let self_test = config
+        .self_test
+        .map(|mode| (mode, config.username.clone(), config.password.clone()));
+    let proxy = MultiIPProxy::new(config);
+
+    if let Some((mode, username, password)) = self_test {
+        match (proxy.self_test(&username, &password), mode) {
+            (Ok(()), _) => info!("Startup self-test passed"),
+            (Err(e), FailureMode::Warn) => warn!("Startup self-test failed: {}", e),
+            (Err(e), FailureMode::Fatal) => {
+                error!("Startup self-test failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(limit) = exit_if_all_down {
+        proxy.spawn_exit_if_all_down(limit);
+    }
+
+    let admin_address = proxy
+        .admin_address
+        .filter(|address| listener_available("Admin", *address, admin_bind_failure));
+    if let Some(admin_address) = admin_address {
+        let mut admin_service = Service::new("Admin".to_string(), proxy.admin_app());
+        admin_service.add_tcp(&admin_address.to_string());
+        server.add_service(admin_service);
+        info!("Admin endpoint listening on {}", admin_address);
+    }
+
+    let metrics_address = proxy
+        .metrics_address
+        .filter(|address| listener_available("Metrics", *address, admin_bind_failure));
+    if let Some(metrics_address) = metrics_address {
+        let mut metrics_service = Service::prometheus_http_service();
+        metrics_service.add_tcp(&metrics_address.to_string());
+        server.add_service(metrics_service);
+        info!("Prometheus metrics listening on {}", metrics_address);
+    }
+
+    let mut proxy_service = http_proxy_service(&server.configuration, proxy);
+    if accept_h2c {
+        let mut server_options = HttpServerOptions::default();
+        server_options.h2c = true;
+        proxy_service
+            .app_logic_mut()
+            .expect("Proxy service has no app logic")
+            .server_options = Some(server_options);
+    }
+    proxy_service.add_tcp(&listen_address);
+
+    server.add_service(proxy_service);
+
+    info!("Server ready to accept connections");
+
+    server
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::ToSocketAddrs;
+
+    fn test_config() -> ProxyConfig {
+        ProxyConfig {
+            ip_addresses: vec!["10.0.0.1".into(), "10.0.0.2".into(), "10.0.0.3".into()],
+            username: "user".into(),
+            password: "secret".into(),
+            listen_address: "127.0.0.1:0".into(),
+            expose_server_timing: false,
+            normalize_upstream_errors: false,
+            pool_active_limit: None,
+            burn_detection: None,
+            request_id_header: None,
+            require_host_header: true,
+            admin_address: None,
+            forward_trailers: true,
+            maintenance: None,
+            metrics_backend: MetricsBackend::None,
+            warn_on_uri_fragment: false,
+            enforce_sni_host_match: false,
+            dns_overrides: DnsOverrides::new(),
+            slow_ttfb_threshold: None,
+            accept_h2c: false,
+            require_e2e_h2: false,
+            default_timeouts: TimeoutProfile::default(),
+            timeout_profiles: Vec::new(),
+            rechunk_close_delimited: true,
+            compression: None,
+            selection_strategy: SelectionStrategy::RoundRobin,
+            max_concurrent_per_principal: None,
+            retry_on_upstream_close: true,
+            client_body_timeout: None,
+            mirror: None,
+            no_keepalive_hosts: Vec::new(),
+            self_test: None,
+            expose_retry_header: false,
+            adaptive_weighting: false,
+            max_conn_age: None,
+            allow_exclude_ip_header: false,
+            admin_bind_failure: FailureMode::Fatal,
+            retry_budget: None,
+            forward_client_info: false,
+            forwarded_for_max_entries: None,
+            retry_on_upstream_silent: true,
+            admin_live_interval: Duration::from_secs(1),
+            fixed_source_port: None,
+            exit_if_all_down: None,
+        }
+    }
+
+    fn burn_detector(cooldown: Duration) -> BurnDetector {
+        BurnDetector::new(BurnDetectionConfig {
+            blocked_ratio: 0.5,
+            window: 4,
+            min_samples: 4,
+            cooldown,
+        })
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        )
+    }
+
+    fn rejected(reason: AuthFailure) -> AuthResult {
+        AuthResult::Unauthenticated { reason }
+    }
+
+    #[test]
+    fn check_credentials_accepts_configured_credentials() {
+        let proxy = MultiIPProxy::new(test_config());
+        assert_eq!(
+            proxy.check_credentials(Some(&basic("user:secret"))),
+            AuthResult::Authenticated {
+                username: "user".into()
+            }
+        );
+    }
+
+    #[test]
+    fn check_credentials_rejects_wrong_password() {
+        let proxy = MultiIPProxy::new(test_config());
+        assert_eq!(
+            proxy.check_credentials(Some(&basic("user:wrong"))),
+            rejected(AuthFailure::Invalid)
+        );
+    }
+
+    #[test]
+    fn check_credentials_rejects_missing_header() {
+        let proxy = MultiIPProxy::new(test_config());
+        assert_eq!(
+            proxy.check_credentials(None),
+            rejected(AuthFailure::Missing)
+        );
+    }
+
+    #[test]
+    fn check_credentials_rejects_empty_header() {
+        let proxy = MultiIPProxy::new(test_config());
+        assert_eq!(
+            proxy.check_credentials(Some("")),
+            rejected(AuthFailure::Empty)
+        );
+        assert_eq!(
+            proxy.check_credentials(Some("  ")),
+            rejected(AuthFailure::Empty)
+        );
+        assert_eq!(AuthFailure::Empty.as_str(), "empty");
+    }
+
+    #[test]
+    fn check_credentials_rejects_bad_base64() {
+        let proxy = MultiIPProxy::new(test_config());
+        assert_eq!(
+            proxy.check_credentials(Some("Basic not*base64!")),
+            rejected(AuthFailure::Invalid)
+        );
+    }
+
+    #[test]
+    fn check_credentials_rejects_other_schemes() {
+        let proxy = MultiIPProxy::new(test_config());
+        let token = base64::engine::general_purpose::STANDARD.encode("user:secret");
+        assert_eq!(
+            proxy.check_credentials(Some(&format!("Bearer {}", token))),
+            rejected(AuthFailure::Invalid)
+        );
+    }
+
+    #[test]
+    fn check_credentials_allows_colon_in_password() {
+        let mut config = test_config();
+        config.password = "pa:ss:word".into();
+        let proxy = MultiIPProxy::new(config);
+
+        assert_eq!(
+            proxy.check_credentials(Some(&basic("user:pa:ss:word"))),
+            AuthResult::Authenticated {
+                username: "user".into()
+            }
+        );
+        assert_eq!(
+            proxy.check_credentials(Some(&basic("user:pa"))),
+            rejected(AuthFailure::Invalid)
+        );
+    }
+
+    #[test]
+    fn burn_detector_waits_for_min_samples() {
+        let detector = burn_detector(Duration::from_secs(60));
+        for _ in 0..3 {
+            detector.record_response("10.0.0.1", 403);
+        }
+        assert!(!detector.is_quarantined("10.0.0.1"));
+
+        detector.record_response("10.0.0.1", 429);
+        assert!(detector.is_quarantined("10.0.0.1"));
+        assert!(detector.quarantine_remaining("10.0.0.1").is_some());
+        assert!(!detector.is_quarantined("10.0.0.2"));
+    }
+
+    #[test]
+    fn burn_detector_ignores_ratio_below_threshold() {
+        let detector = burn_detector(Duration::from_secs(60));
+        for status in [403, 200, 200, 200, 200, 200] {
+            detector.record_response("10.0.0.1", status);
+        }
+        assert!(!detector.is_quarantined("10.0.0.1"));
+    }
+
+    #[test]
+    fn burn_detector_window_drops_old_responses() {
+        let detector = burn_detector(Duration::from_secs(60));
+        for status in [403, 200, 200, 200, 403] {
+            detector.record_response("10.0.0.1", status);
+        }
+        // Only the last four count: one 403 among them
+        assert!(!detector.is_quarantined("10.0.0.1"));
+
+        detector.record_response("10.0.0.1", 403);
+        assert!(detector.is_quarantined("10.0.0.1"));
+    }
+
+    #[test]
+    fn burn_detector_releases_after_cooldown() {
+        let detector = burn_detector(Duration::ZERO);
+        for _ in 0..4 {
+            detector.record_response("10.0.0.1", 403);
+        }
+        assert!(!detector.is_quarantined("10.0.0.1"));
+        assert_eq!(detector.quarantine_remaining("10.0.0.1"), None);
+    }
+
+    #[test]
+    fn all_down_watch_needs_every_ip_down_for_the_limit() {
+        let ips = vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()];
+        let limit = Duration::from_secs(30);
+        let one_down = burn_detector(Duration::from_secs(600));
+        let all_down = burn_detector(Duration::from_secs(600));
+        for _ in 0..4 {
+            one_down.record_response("10.0.0.1", 403);
+            for ip in &ips {
+                all_down.record_response(ip, 403);
+            }
+        }
+
+        let start = Instant::now();
+        let mut watch = AllDownWatch::new(ips, limit);
+        assert!(!watch.limit_exceeded(&one_down, start));
+        assert!(!watch.limit_exceeded(&one_down, start + limit * 2));
+
+        // All down, but not yet for the whole limit
+        assert!(!watch.limit_exceeded(&all_down, start));
+        assert!(!watch.limit_exceeded(&all_down, start + limit - Duration::from_secs(1)));
+        assert!(watch.limit_exceeded(&all_down, start + limit));
+
+        // One IP recovering restarts the clock
+        assert!(!watch.limit_exceeded(&one_down, start + limit));
+        assert!(!watch.limit_exceeded(&all_down, start + limit * 2 - Duration::from_secs(1)));
+        assert!(watch.limit_exceeded(&all_down, start + limit * 3 - Duration::from_secs(1)));
+    }
+
+    /// Returns the raw JSON value of `key` in `object`, stopping at the first top-level
+    /// `,` or `}` after it.
+    fn json_value<'a>(object: &'a str, key: &str) -> &'a str {
+        let start = object.find(&format!("\"{}\":", key)).unwrap() + key.len() + 3;
+        let mut depth = 0;
+        for (offset, c) in object[start..].char_indices() {
+            match c {
+                '{' | '[' => depth += 1,
+                '}' | ']' if depth > 0 => depth -= 1,
+                ',' | '}' | ']' if depth == 0 => return &object[start..start + offset],
+                _ => {}
+            }
+        }
+        &object[start..]
+    }
+
+    /// Returns the object in the snapshot's `ips` array describing `ip`.
+    fn snapshot_entry<'a>(snapshot: &'a str, ip: &str) -> &'a str {
+        let start = snapshot.find(&format!("{{\"ip\":\"{}\"", ip)).unwrap();
+        let mut depth = 0;
+        for (offset, c) in snapshot[start..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 1 => return &snapshot[start..=start + offset],
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        panic!("unterminated entry for {}", ip)
+    }
+
+    #[test]
+    fn snapshot_json_reports_each_ip() {
+        let ips = vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()];
+        let stats = EgressStats::new(&ips);
+        let detector = burn_detector(Duration::from_secs(60));
+
+        for _ in 0..3 {
+            stats.request_started("10.0.0.1");
+        }
+        stats.request_finished("10.0.0.1", 200, Some(Duration::from_millis(10)));
+        stats.request_finished("10.0.0.1", 404, Some(Duration::from_millis(30)));
+
+        for _ in 0..5 {
+            stats.request_started("10.0.0.2");
+        }
+        for _ in 0..4 {
+            stats.request_finished("10.0.0.2", 403, Some(Duration::from_millis(5)));
+            detector.record_response("10.0.0.2", 403);
+        }
+        stats.request_finished("10.0.0.2", 0, None);
+
+        let snapshot = stats.snapshot_json(&ips, Some(&detector));
+        assert!(snapshot.starts_with("{\"ips\":[") && snapshot.ends_with("]}"));
+
+        let healthy = snapshot_entry(&snapshot, "10.0.0.1");
+        assert_eq!(json_value(healthy, "active_requests"), "1");
+        assert_eq!(
+            json_value(healthy, "responses"),
+            "{\"1xx\":0,\"2xx\":1,\"3xx\":0,\"4xx\":1,\"5xx\":0,\"failed\":0}"
+        );
+        assert_eq!(json_value(healthy, "avg_latency_ms"), "20.000");
+        assert_eq!(json_value(healthy, "quarantined"), "false");
+        assert_eq!(json_value(healthy, "quarantine_remaining_secs"), "0");
+
+        let burned = snapshot_entry(&snapshot, "10.0.0.2");
+        assert_eq!(json_value(burned, "active_requests"), "0");
+        assert_eq!(
+            json_value(burned, "responses"),
+            "{\"1xx\":0,\"2xx\":0,\"3xx\":0,\"4xx\":4,\"5xx\":0,\"failed\":1}"
+        );
+        assert_eq!(json_value(burned, "avg_latency_ms"), "5.000");
+        assert_eq!(json_value(burned, "quarantined"), "true");
+        let remaining: u64 = json_value(burned, "quarantine_remaining_secs")
+            .parse()
+            .unwrap();
+        assert!((59..=60).contains(&remaining));
+
+        // Without burn detection every IP reads as healthy
+        let snapshot = stats.snapshot_json(&ips, None);
+        let burned = snapshot_entry(&snapshot, "10.0.0.2");
+        assert_eq!(json_value(burned, "quarantined"), "false");
+    }
+
+    #[test]
+    fn request_id_tag_needs_header_and_id() {
+        let mut config = test_config();
+        config.request_id_header = Some("X-Request-Id".into());
+        let proxy = MultiIPProxy::new(config);
+        let mut ctx = RequestContext::default();
+        assert_eq!(proxy.request_id_tag(&ctx), None);
+
+        ctx.request_id = Some(proxy.generate_request_id());
+        let (name, id) = proxy.request_id_tag(&ctx).unwrap();
+        assert_eq!(name, "X-Request-Id");
+        assert_ne!(id, proxy.generate_request_id());
+
+        let proxy = MultiIPProxy::new(test_config());
+        assert_eq!(proxy.request_id_tag(&ctx), None);
+    }
+
+    #[test]
+    fn resolve_request_id_keeps_client_id_under_custom_header() {
+        let mut config = test_config();
+        config.request_id_header = Some("X-Correlation-Id".into());
+        let proxy = MultiIPProxy::new(config);
+
+        let mut request = get("/");
+        request
+            .insert_header("x-correlation-id", "client-abc-123")
+            .unwrap();
+        assert_eq!(
+            proxy.resolve_request_id(&request),
+            ("client-abc-123".to_string(), true)
+        );
+
+        // The default name is not consulted once another one is configured
+        let mut request = get("/");
+        request.insert_header("X-Request-Id", "ignored").unwrap();
+        let (first, preserved) = proxy.resolve_request_id(&request);
+        assert!(!preserved);
+        assert_ne!(first, "ignored");
+        let (second, _) = proxy.resolve_request_id(&request);
+        assert_ne!(first, second);
+    }
+
+    fn management_proxy(admin_address: &str) -> MultiIPProxy {
+        let mut config = test_config();
+        config.admin_address = Some(admin_address.parse().unwrap());
+        MultiIPProxy::new(config)
+    }
+
+    /// Resolves the target the way `upstream_peer` does and checks the resulting address.
+    fn targets_management(proxy: &MultiIPProxy, host: &str, port: u16) -> bool {
+        let target = TargetInfo {
+            host: host.to_string(),
+            port,
+            use_tls: false,
+        };
+        let peer = create_http_peer(&target, None).unwrap();
+        proxy.is_management_address(*peer.address().as_inet().unwrap())
+    }
+
+    #[test]
+    fn management_address_blocks_loopback_spellings() {
+        let proxy = management_proxy("127.0.0.1:19090");
+        for host in [
+            "127.0.0.1",
+            "127.1",
+            "2130706433",
+            "[::1]",
+            "[::ffff:127.0.0.1]",
+            "localhost",
+        ] {
+            assert!(targets_management(&proxy, host, 19090), "{}", host);
+        }
+
+        // The root-qualified name needs a resolver, which sandboxed hosts may not have
+        if let Ok(addresses) = ("localhost.", 19090).to_socket_addrs() {
+            for address in addresses {
+                assert!(proxy.is_management_address(address), "{}", address);
+            }
+        }
+    }
+
+    #[test]
+    fn management_address_blocks_unspecified_and_local_addresses() {
+        let proxy = management_proxy("0.0.0.0:19090");
+        assert!(targets_management(&proxy, "0.0.0.0", 19090));
+        assert!(targets_management(&proxy, "[::]", 19090));
+
+        // An address of one of this host's interfaces, when it has a route out
+        let local_ip = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect("192.0.2.1:9").map(|_| socket))
+            .and_then(|socket| socket.local_addr())
+            .map(|address| address.ip());
+        if let Ok(local_ip) = local_ip {
+            assert!(
+                proxy.is_management_address(SocketAddr::new(local_ip, 19090)),
+                "{}",
+                local_ip
+            );
+        }
+    }
+
+    #[test]
+    fn management_address_allows_other_targets() {
+        let proxy = management_proxy("0.0.0.0:19090");
+        assert!(!targets_management(&proxy, "127.0.0.1", 19091));
+        assert!(!proxy.is_management_address("192.0.2.10:19090".parse().unwrap()));
+        assert!(!MultiIPProxy::new(test_config())
+            .is_management_address("127.0.0.1:19090".parse().unwrap()));
+    }
+
+    #[test]
+    fn unresolvable_target_fails_with_bad_gateway() {
+        let target = TargetInfo {
+            host: "no-such-host.invalid".to_string(),
+            port: 80,
+            use_tls: false,
+        };
+        let error = create_http_peer(&target, None).unwrap_err();
+        assert_eq!(error.etype(), &HTTPStatus(502));
+
+        let overridden = create_http_peer(&target, Some("192.0.2.10".parse().unwrap())).unwrap();
+        assert_eq!(
+            overridden.address().as_inet(),
+            Some(&"192.0.2.10:80".parse().unwrap())
+        );
+    }
+
+    fn request_with_te(values: &[&str]) -> RequestHeader {
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        for value in values {
+            request.append_header("TE", *value).unwrap();
+        }
+        request
+    }
+
+    #[test]
+    fn accepts_trailers_parses_te_tokens() {
+        assert!(accepts_trailers(&request_with_te(&["trailers"])));
+        assert!(accepts_trailers(&request_with_te(&["gzip, Trailers"])));
+        assert!(accepts_trailers(&request_with_te(&[
+            "gzip",
+            "trailers;q=1"
+        ])));
+        assert!(!accepts_trailers(&request_with_te(&[])));
+        assert!(!accepts_trailers(&request_with_te(&["gzip, deflate"])));
+        assert!(!accepts_trailers(&request_with_te(&["x-trailers"])));
+    }
+
+    #[test]
+    fn strip_fragment_cuts_at_first_hash() {
+        assert_eq!(
+            strip_fragment(b"example.com#frag"),
+            Some(&b"example.com"[..])
+        );
+        assert_eq!(
+            strip_fragment(b"example.com:8080#a#b"),
+            Some(&b"example.com:8080"[..])
+        );
+        assert_eq!(strip_fragment(b"#"), Some(&b""[..]));
+        assert_eq!(strip_fragment(b"example.com"), None);
+    }
+
+    #[test]
+    fn request_target_fragment_never_reaches_upstream() {
+        assert!(raw_target_has_fragment(
+            b"GET /path?q=1#frag HTTP/1.1\r\nHost: example.com\r\n\r\n"
+        ));
+        assert!(!raw_target_has_fragment(
+            b"GET /path?q=1 HTTP/1.1\r\nHost: example.com#frag\r\n\r\n"
+        ));
+
+        // The parsed target the upstream request is built from has already lost it
+        let request = RequestHeader::build("GET", b"/path?q=1#frag", None).unwrap();
+        assert_eq!(request.raw_path(), b"/path?q=1");
+    }
+
+    fn tls_request(authority: &str, host_header: Option<&str>) -> RequestHeader {
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        request.set_uri(format!("https://{}/", authority).parse().unwrap());
+        if let Some(host) = host_header {
+            request.insert_header("Host", host).unwrap();
+        }
+        request
+    }
+
+    #[test]
+    fn sni_matches_host_rejects_fronting() {
+        let request = tls_request("benign.example", Some("evil.example"));
+        assert!(!sni_matches_host("benign.example", &request));
+    }
+
+    #[test]
+    fn sni_matches_host_accepts_matching_values() {
+        let request = tls_request("api.example:8443", Some("API.example:8443"));
+        assert!(sni_matches_host("api.example", &request));
+
+        // h2 requests usually carry only :authority
+        let request = tls_request("api.example", None);
+        assert!(sni_matches_host("api.example", &request));
+    }
+
+    #[test]
+    fn sni_matches_host_fails_closed() {
+        let request = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(!sni_matches_host("localhost", &request));
+
+        let request = tls_request("api.example", Some("not a host"));
+        assert!(!sni_matches_host("api.example", &request));
+    }
+
+    #[test]
+    fn host_matches_pattern_exact_and_wildcard() {
+        assert!(host_matches_pattern("example.com", "example.com"));
+        assert!(!host_matches_pattern("www.example.com", "example.com"));
+        assert!(host_matches_pattern("api.example.com", "*.example.com"));
+        assert!(host_matches_pattern("a.b.example.com", "*.example.com"));
+        assert!(!host_matches_pattern("example.com", "*.example.com"));
+        assert!(!host_matches_pattern("badexample.com", "*.example.com"));
+    }
+
+    #[test]
+    fn parse_timeout_profiles_reads_entries() {
+        let profiles = ProxyConfig::parse_timeout_profiles(
+            " *.Slow.example=read:30000;connect:500 , fast.example=write:10",
+        )
+        .unwrap();
+
+        assert_eq!(profiles.len(), 2);
+        let (pattern, slow) = &profiles[0];
+        assert_eq!(pattern, "*.slow.example");
+        assert_eq!(slow.read, Some(Duration::from_millis(30000)));
+        assert_eq!(slow.connect, Some(Duration::from_millis(500)));
+        assert_eq!(slow.write, None);
+        assert_eq!(profiles[1].1.write, Some(Duration::from_millis(10)));
+
+        assert!(ProxyConfig::parse_timeout_profiles("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_timeout_profiles_rejects_malformed_entries() {
+        for raw in [
+            "example.com",
+            "example.com=read",
+            "example.com=read:soon",
+            "example.com=idle:100",
+        ] {
+            assert!(ProxyConfig::parse_timeout_profiles(raw).is_err(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn timeouts_for_uses_first_match_over_defaults() {
+        let mut config = test_config();
+        config.default_timeouts = TimeoutProfile {
+            connect: Some(Duration::from_secs(1)),
+            read: Some(Duration::from_secs(2)),
+            write: None,
+        };
+        config.timeout_profiles =
+            ProxyConfig::parse_timeout_profiles("*.example.com=read:100,api.example.com=read:200")
+                .unwrap();
+        let proxy = MultiIPProxy::new(config);
+
+        let timeouts = proxy.timeouts_for("API.example.com");
+        assert_eq!(timeouts.read, Some(Duration::from_millis(100)));
+        assert_eq!(timeouts.connect, Some(Duration::from_secs(1)));
+        assert_eq!(
+            proxy.timeouts_for("other.org").read,
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    fn compressing_proxy() -> MultiIPProxy {
+        let mut config = test_config();
+        config.compression = Some(CompressionConfig {
+            min_bytes: 1024,
+            content_types: vec!["text/".into(), "application/json".into()],
+        });
+        MultiIPProxy::new(config)
+    }
+
+    fn response(status: u16, content_type: &str, length: Option<usize>) -> ResponseHeader {
+        let mut response = ResponseHeader::build(status, None).unwrap();
+        response
+            .insert_header("Content-Type", content_type)
+            .unwrap();
+        if let Some(length) = length {
+            response
+                .insert_header("Content-Length", length.to_string())
+                .unwrap();
+        }
+        response
+    }
+
+    #[test]
+    fn should_compress_large_allowed_types() {
+        let proxy = compressing_proxy();
+        let get = RequestHeader::build("GET", b"/", None).unwrap();
+
+        assert!(proxy.should_compress(&get, &response(200, "text/html; charset=utf-8", Some(4096))));
+        assert!(proxy.should_compress(&get, &response(200, "Application/JSON", None)));
+        assert!(!proxy.should_compress(&get, &response(200, "text/plain", Some(100))));
+        assert!(!proxy.should_compress(&get, &response(200, "image/png", Some(4096))));
+        assert!(!proxy.should_compress(&get, &response(304, "text/html", Some(4096))));
+
+        let head = RequestHeader::build("HEAD", b"/", None).unwrap();
+        assert!(!proxy.should_compress(&head, &response(200, "text/html", Some(4096))));
+
+        let proxy = MultiIPProxy::new(test_config());
+        assert!(!proxy.should_compress(&get, &response(200, "text/html", Some(4096))));
+    }
+
+    fn get(path: &str) -> RequestHeader {
+        RequestHeader::build("GET", path.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn round_robin_cycles_through_the_pool() {
+        let proxy = MultiIPProxy::new(test_config());
+        let picks: Vec<&str> = (0..4)
+            .map(|_| proxy.select_next_ip(&get("/"), 0, &[]))
+            .collect();
+        assert_eq!(picks, ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.1"]);
+    }
+
+    #[test]
+    fn hash_path_is_sticky_per_path_and_moves_on_retry() {
+        let mut config = test_config();
+        config.selection_strategy = SelectionStrategy::HashPath;
+        let proxy = MultiIPProxy::new(config);
+
+        let first = proxy.select_next_ip(&get("/assets/app.js"), 0, &[]);
+        for _ in 0..5 {
+            assert_eq!(
+                proxy.select_next_ip(&get("/assets/app.js?v=2"), 0, &[]),
+                first
+            );
+        }
+
+        let retry = proxy.select_next_ip(&get("/assets/app.js"), 1, &[]);
+        assert_ne!(retry, first);
+    }
+
+    #[test]
+    fn select_next_ip_skips_excluded_ips() {
+        let proxy = MultiIPProxy::new(test_config());
+        let excluded = ["10.0.0.1".to_string(), "10.0.0.3".to_string()];
+        for _ in 0..4 {
+            assert_eq!(proxy.select_next_ip(&get("/"), 0, &excluded), "10.0.0.2");
+        }
+    }
+
+    #[test]
+    fn select_next_ip_wraps_the_request_counter() {
+        let proxy = MultiIPProxy::new(test_config());
+        proxy.request_counter.store(usize::MAX, Ordering::Relaxed);
+        let excluded = ["10.0.0.1".to_string()];
+
+        let ip = proxy.select_next_ip(&get("/"), 0, &excluded);
+        assert_ne!(ip, "10.0.0.1");
+        assert_eq!(proxy.request_counter.load(Ordering::Relaxed), 0);
+    }
+
+    fn mirror(target: &str, percent: u64) -> TrafficMirror {
+        TrafficMirror::new(MirrorConfig {
+            target: target.to_string(),
+            percent,
+        })
+    }
+
+    #[test]
+    fn should_mirror_samples_the_configured_share() {
+        for percent in [0, 1, 25, 50, 100] {
+            let mirror = mirror("127.0.0.1:9", percent);
+            let mirrored = (0..1000).filter(|_| mirror.should_mirror()).count();
+            assert_eq!(mirrored as u64, percent * 10, "{}%", percent);
+        }
+
+        // Spread out rather than bunched at the start
+        let mirror = mirror("127.0.0.1:9", 50);
+        let picks: Vec<bool> = (0..4).map(|_| mirror.should_mirror()).collect();
+        assert_eq!(picks, [false, true, false, true]);
+    }
+
+    #[tokio::test]
+    async fn mirror_drops_requests_when_full() {
+        let shadow = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mirror = mirror(&shadow.local_addr().unwrap().to_string(), 100);
+
+        let held = mirror
+            .in_flight
+            .clone()
+            .acquire_many_owned(MIRROR_MAX_IN_FLIGHT as u32)
+            .await
+            .unwrap();
+        mirror.spawn(&get("/dropped"));
+        let accepted = tokio::time::timeout(Duration::from_millis(200), shadow.accept()).await;
+        assert!(accepted.is_err(), "mirror should have been dropped");
+
+        drop(held);
+        mirror.spawn(&get("/sent"));
+        let accepted = tokio::time::timeout(Duration::from_secs(2), shadow.accept()).await;
+        assert!(accepted.is_ok(), "mirror should have been sent");
+    }
+
+    #[test]
+    fn no_keepalive_hosts_match_case_insensitively() {
+        let mut config = test_config();
+        config.no_keepalive_hosts = vec!["legacy.example".into(), "*.flaky.example".into()];
+        let proxy = MultiIPProxy::new(config);
+
+        assert!(proxy.is_no_keepalive_host("Legacy.Example"));
+        assert!(proxy.is_no_keepalive_host("api.FLAKY.example"));
+        assert!(!proxy.is_no_keepalive_host("flaky.example"));
+        assert!(!proxy.is_no_keepalive_host("other.example"));
+    }
+
+    fn local_proxy(username: &str, password: &str) -> MultiIPProxy {
+        let mut config = test_config();
+        config.ip_addresses = vec!["127.0.0.1".into()];
+        config.username = username.into();
+        config.password = password.into();
+        MultiIPProxy::new(config)
+    }
+
+    #[test]
+    fn self_test_passes_working_config() {
+        assert_eq!(
+            local_proxy("user", "secret").self_test("user", "secret"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn self_test_detects_broken_credentials() {
+        let proxy = local_proxy("user", "secret");
+        assert!(proxy.self_test("user", "").is_err());
+        assert!(proxy.self_test("", "secret").is_err());
+        // The proxy was built with other credentials, so the configured pair is rejected
+        assert!(proxy.self_test("user", "other").is_err());
+
+        let proxy = local_proxy("us:er", "secret");
+        assert!(proxy.self_test("us:er", "secret").is_err());
+    }
+
+    #[test]
+    fn self_test_checks_every_egress_ip() {
+        let mut config = test_config();
+        config.ip_addresses = vec!["127.0.0.1".into(), "192.0.2.1".into()];
+        let proxy = MultiIPProxy::new(config);
+
+        let error = proxy.self_test("user", "secret").unwrap_err();
+        assert!(error.contains("192.0.2.1"), "{}", error);
+    }
+
+    #[test]
+    fn validate_rejects_bad_pool_entries_and_usernames() {
+        assert_eq!(test_config().validate(), Ok(()));
+
+        let mut config = test_config();
+        config.ip_addresses.push("10.0.0.300".into());
+        assert!(config.validate().unwrap_err().contains("10.0.0.300"));
+
+        let mut config = test_config();
+        config.username = "us:er".into();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn weighted_selection_favours_successful_ips() {
+        let mut config = test_config();
+        config.adaptive_weighting = true;
+        let proxy = MultiIPProxy::new(config);
+        for _ in 0..200 {
+            proxy.egress_stats.request_started("10.0.0.1");
+            proxy.egress_stats.request_finished("10.0.0.1", 502, None);
+        }
+
+        let mut picks = HashMap::new();
+        for request_number in 0..3000 {
+            let ip = proxy.select_weighted_ip(request_number, &[]).unwrap();
+            *picks.entry(ip).or_insert(0) += 1;
+        }
+        // A failing IP keeps only its floor weight, the others split the rest evenly
+        assert!(picks["10.0.0.1"] < 150, "{:?}", picks);
+        assert!(picks["10.0.0.2"] > 1300, "{:?}", picks);
+        assert!(picks["10.0.0.3"] > 1300, "{:?}", picks);
+    }
+
+    #[test]
+    fn weighted_selection_skips_excluded_and_quarantined_ips() {
+        let mut config = test_config();
+        config.burn_detection = Some(BurnDetectionConfig {
+            blocked_ratio: 0.5,
+            window: 1,
+            min_samples: 1,
+            cooldown: Duration::from_secs(60),
+        });
+        let proxy = MultiIPProxy::new(config);
+        proxy
+            .burn_detector
+            .as_ref()
+            .unwrap()
+            .record_response("10.0.0.2", 403);
+        let excluded = ["10.0.0.1".to_string()];
+
+        for request_number in 0..50 {
+            assert_eq!(
+                proxy.select_weighted_ip(request_number, &excluded),
+                Some("10.0.0.3")
+            );
+        }
+
+        let excluded = ["10.0.0.1".to_string(), "10.0.0.3".to_string()];
+        assert_eq!(proxy.select_weighted_ip(0, &excluded), None);
+    }
+
+    #[test]
+    fn conflicting_framing_needs_both_headers() {
+        assert!(has_both_framing_headers(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n"
+        ));
+        assert!(has_both_framing_headers(
+            b"POST / HTTP/1.1\r\ntransfer-encoding : chunked\r\ncontent-length:5\r\n\r\n"
+        ));
+        assert!(!has_both_framing_headers(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n"
+        ));
+        assert!(!has_both_framing_headers(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nX-Note: Content-Length: 5\r\n\r\n"
+        ));
+        // The request line is never read as a header
+        assert!(!has_both_framing_headers(
+            b"Content-Length: / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"
+        ));
+    }
+
+    #[test]
+    fn exclude_ip_header_keeps_only_pool_ips() {
+        let proxy = MultiIPProxy::new(test_config());
+        let mut request = get("/");
+        assert!(proxy.excluded_ips(&request).is_empty());
+
+        request
+            .insert_header("X-Proxy-Exclude-IP", " 10.0.0.3 ,192.0.2.1,10.0.0.1,")
+            .unwrap();
+        assert_eq!(proxy.excluded_ips(&request), ["10.0.0.1", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn listener_available_warns_on_taken_address() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = taken.local_addr().unwrap();
+        assert!(!listener_available("Admin", address, FailureMode::Warn));
+
+        drop(taken);
+        assert!(listener_available("Admin", address, FailureMode::Warn));
+    }
+
+    fn retry_budget(retries: u32, interval: Duration) -> RetryBudget {
+        RetryBudget::new(RetryBudgetConfig { retries, interval })
+    }
+
+    #[test]
+    fn retry_budget_is_per_host() {
+        let budget = retry_budget(2, Duration::from_secs(3600));
+        assert!(budget.try_consume("a.example"));
+        assert!(budget.try_consume("a.example"));
+        assert!(!budget.try_consume("a.example"));
+        assert!(budget.try_consume("b.example"));
+    }
+
+    #[test]
+    fn retry_budget_refills_over_the_interval() {
+        let budget = retry_budget(1, Duration::from_millis(50));
+        assert!(budget.try_consume("a.example"));
+        assert!(!budget.try_consume("a.example"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(budget.try_consume("a.example"));
+    }
+
+    #[test]
+    fn retry_budget_forgets_refilled_hosts_past_the_cap() {
+        let budget = retry_budget(1, Duration::from_millis(10));
+        for host in 0..RETRY_BUDGET_MAX_HOSTS {
+            budget.try_consume(&format!("host-{}.example", host));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert!(budget.try_consume("new.example"));
+        assert_eq!(budget.buckets.lock().unwrap().len(), 1);
+    }
+
+    fn forwarded_for(values: &[&str]) -> RequestHeader {
+        let mut request = get("/");
+        for value in values {
+            request.append_header("X-Forwarded-For", *value).unwrap();
+        }
+        request
+    }
+
+    #[test]
+    fn forwarded_for_canonicalizes_and_appends_client() {
+        let request = forwarded_for(&[
+            "203.0.113.1:8080, unknown, \"[2001:db8::1]:443\"",
+            "::ffff:198.51.100.7",
+        ]);
+        let client = "192.0.2.9".parse().ok();
+        assert_eq!(
+            normalize_forwarded_for(&request, client, None),
+            "203.0.113.1, 2001:db8::1, 198.51.100.7, 192.0.2.9"
+        );
+    }
+
+    #[test]
+    fn forwarded_for_keeps_last_position_of_duplicates() {
+        let request = forwarded_for(&["192.0.2.9, 203.0.113.1, 192.0.2.9"]);
+        let client = "192.0.2.9".parse().ok();
+        assert_eq!(
+            normalize_forwarded_for(&request, client, None),
+            "203.0.113.1, 192.0.2.9"
+        );
+    }
+
+    #[test]
+    fn forwarded_for_limits_to_most_recent_hops() {
+        let request = forwarded_for(&["203.0.113.1, 203.0.113.2, 203.0.113.3"]);
+        let client = "192.0.2.9".parse().ok();
+        assert_eq!(
+            normalize_forwarded_for(&request, client, Some(2)),
+            "203.0.113.3, 192.0.2.9"
+        );
+        assert_eq!(normalize_forwarded_for(&get("/"), None, None), "");
+    }
+
+    fn masked_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = websocket_frame(opcode, payload);
+        let header_len = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        let masked: Vec<u8> = payload
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ mask[index % 4])
+            .collect();
+        frame.truncate(header_len);
+        frame.extend_from_slice(&mask);
+        frame.extend_from_slice(&masked);
+        frame
+    }
+
+    #[test]
+    fn websocket_accept_matches_rfc_example() {
+        assert_eq!(
+            websocket_accept_value(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn websocket_frame_encodes_each_length_form() {
+        assert_eq!(websocket_frame(0x1, b"hi"), [0x81, 2, b'h', b'i']);
+
+        let frame = websocket_frame(0x1, &[0; 126]);
+        assert_eq!(frame[..4], [0x81, 126, 0, 126]);
+        assert_eq!(frame.len(), 4 + 126);
+
+        let frame = websocket_frame(0x2, &[0; 0x10000]);
+        assert_eq!(frame[..10], [0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(frame.len(), 10 + 0x10000);
+    }
+
+    #[test]
+    fn next_client_frame_unmasks_and_consumes() {
+        let mut buffer = masked_frame(0x9, b"ping");
+        buffer.extend(masked_frame(0x8, &[0x03, 0xe8]));
+
+        assert_eq!(
+            next_client_frame(&mut buffer),
+            Some((0x9, b"ping".to_vec()))
+        );
+        assert_eq!(
+            next_client_frame(&mut buffer),
+            Some((0x8, vec![0x03, 0xe8]))
+        );
+        assert_eq!(next_client_frame(&mut buffer), None);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn next_client_frame_waits_for_complete_frames() {
+        let large = vec![7; 300];
+        let full = masked_frame(0x2, &large);
+        for cut in [1, 3, 7, full.len() - 1] {
+            let mut buffer = full[..cut].to_vec();
+            assert_eq!(next_client_frame(&mut buffer), None, "{}", cut);
+            assert_eq!(buffer.len(), cut);
+        }
+
+        let mut buffer = full;
+        assert_eq!(next_client_frame(&mut buffer), Some((0x2, large)));
+    }
+
+    fn fixed_source_port() -> FixedSourcePort {
+        let config = FixedSourcePortConfig {
+            port: 40000,
+            wait: Duration::from_millis(50),
+        };
+        FixedSourcePort::new(config, &["10.0.0.1".to_string(), "10.0.0.2".to_string()])
+    }
+
+    #[test]
+    fn fixed_source_port_binds_and_disables_pooling() {
+        let mut peer = HttpPeer::new("192.0.2.1:80", false, String::new());
+        fixed_source_port().apply(&mut peer, "10.0.0.2");
+
+        let bind_to = peer.options.bind_to.as_ref().unwrap();
+        assert_eq!(bind_to.addr, Some("10.0.0.2:40000".parse().unwrap()));
+        assert_eq!(peer.options.idle_timeout, Some(Duration::ZERO));
+        assert!(peer.options.upstream_tcp_sock_tweak_hook.is_some());
+    }
+
+    #[tokio::test]
+    async fn fixed_source_port_serializes_each_ip() {
+        let fixed = fixed_source_port();
+        let permit = fixed.acquire("10.0.0.1").await;
+        assert!(permit.is_some());
+
+        assert!(fixed.acquire("10.0.0.1").await.is_none());
+        assert!(fixed.acquire("10.0.0.2").await.is_some());
+        assert!(fixed.acquire("192.0.2.1").await.is_none());
+
+        drop(permit);
+        assert!(fixed.acquire("10.0.0.1").await.is_some());
+    }
+
+    #[test]
+    fn no_response_failures_retry_idempotent_requests() {
+        let proxy = MultiIPProxy::new(test_config());
+        let mut ctx = RequestContext::default();
+
+        let silent = Error::new_up(ReadTimedout);
+        assert!(proxy.classify_no_response(&silent, &Method::GET, true, &mut ctx));
+        assert!(ctx.upstream_silent && !ctx.upstream_closed_early);
+        assert_eq!(
+            proxy_failure_status(&silent, &ctx),
+            Some((504, "Gateway Timeout: upstream silent"))
+        );
+
+        // Not idempotent, or the body can no longer be replayed
+        assert!(!proxy.classify_no_response(&silent, &Method::POST, true, &mut ctx));
+        assert!(!proxy.classify_no_response(&silent, &Method::GET, false, &mut ctx));
+        assert!(ctx.upstream_silent);
+
+        let closed = Error::new_up(ConnectionClosed);
+        assert!(proxy.classify_no_response(&closed, &Method::GET, true, &mut ctx));
+        assert!(ctx.upstream_closed_early && !ctx.upstream_silent);
+        assert_eq!(proxy_failure_status(&closed, &ctx).unwrap().0, 502);
+
+        // A later attempt failing another way leaves no stale flag behind
+        let refused = Error::new_up(ConnectRefused);
+        assert!(!proxy.classify_no_response(&refused, &Method::GET, true, &mut ctx));
+        assert!(!ctx.upstream_silent && !ctx.upstream_closed_early);
+        assert_eq!(proxy_failure_status(&refused, &ctx), None);
+
+        // A timeout after response bytes arrived is a mid-response failure
+        ctx.ttfb = Some(Duration::from_millis(5));
+        assert!(!proxy.classify_no_response(&silent, &Method::GET, true, &mut ctx));
+        assert!(!ctx.upstream_silent);
+    }
+
+    #[test]
+    fn no_response_failures_respect_switches_and_remaining_ips() {
+        let mut config = test_config();
+        config.retry_on_upstream_silent = false;
+        let proxy = MultiIPProxy::new(config);
+        let mut ctx = RequestContext::default();
+
+        let silent = Error::new_up(ReadTimedout);
+        assert!(!proxy.classify_no_response(&silent, &Method::GET, true, &mut ctx));
+        assert!(ctx.upstream_silent);
+        assert!(proxy.classify_no_response(
+            &Error::new_up(ConnectionClosed),
+            &Method::GET,
+            true,
+            &mut ctx
+        ));
+
+        // Two of three IPs already tried: the one that just failed is the last
+        ctx.excluded_ips = vec!["10.0.0.1".into(), "10.0.0.2".into()];
+        assert!(!proxy.classify_no_response(
+            &Error::new_up(ConnectionClosed),
+            &Method::GET,
+            true,
+            &mut ctx
+        ));
+    }
+
+    #[test]
+    fn client_body_timeout_answers_408() {
+        let mut ctx = RequestContext::default();
+        let timeout = Error::new_down(ReadTimedout);
+        assert_eq!(proxy_failure_status(&timeout, &ctx), None);
+
+        ctx.client_body_deadline = Some(Instant::now());
+        assert_eq!(proxy_failure_status(&timeout, &ctx).unwrap().0, 408);
+        assert_eq!(
+            proxy_failure_status(&Error::new_up(ReadTimedout), &ctx),
+            None
+        );
+    }
+
+    #[test]
+    fn read_timeout_defaults_while_silent_retry_is_on() {
+        // The only test touching the environment, so nothing races with it
+        let load = |silent: Option<&str>, read: Option<&str>| {
+            for (name, value) in [
+                ("RETRY_ON_UPSTREAM_SILENT", silent),
+                ("READ_TIMEOUT_MS", read),
+            ] {
+                match value {
+                    Some(value) => env::set_var(name, value),
+                    None => env::remove_var(name),
+                }
+            }
+            let config = ProxyConfig::load_from_environment().unwrap();
+            env::remove_var("RETRY_ON_UPSTREAM_SILENT");
+            env::remove_var("READ_TIMEOUT_MS");
+            config.default_timeouts.read
+        };
+
+        assert_eq!(load(None, None), Some(SILENT_UPSTREAM_READ_TIMEOUT));
+        assert_eq!(
+            load(Some("true"), Some("2500")),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(load(Some("false"), None), None);
+        assert_eq!(
+            load(Some("false"), Some("2500")),
+            Some(Duration::from_millis(2500))
+        );
+    }
+
+    #[test]
+    fn server_timing_header_lists_measured_phases() {
+        let mut ctx = RequestContext::default();
+        assert_eq!(ctx.server_timing_header(), None);
+
+        ctx.connect_duration = Some(Duration::from_micros(1500));
+        ctx.ttfb = Some(Duration::from_millis(42));
+        assert_eq!(
+            ctx.server_timing_header().as_deref(),
+            Some("connect;dur=1.500, ttfb;dur=42.000")
+        );
+
+        ctx.upstream_duration = Some(Duration::from_millis(20));
+        assert_eq!(
+            ctx.server_timing_header().as_deref(),
+            Some("connect;dur=1.500, upstream;dur=20.000, ttfb;dur=42.000")
+        );
+    }
+
+    #[test]
+    fn pool_active_limit_keeps_rotation_on_the_first_ips() {
+        let mut config = test_config();
+        config.ip_addresses = (1..=10).map(|n| format!("10.0.0.{}", n)).collect();
+        config.pool_active_limit = Some(3);
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.reserve_ip_addresses().len(), 7);
+
+        let proxy = MultiIPProxy::new(config);
+        let picks: Vec<&str> = (0..7)
+            .map(|_| proxy.select_next_ip(&get("/"), 0, &[]))
+            .collect();
+        assert_eq!(
+            picks,
+            ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.1"]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_pool_active_limit_outside_the_pool() {
+        for limit in [0, 4] {
+            let mut config = test_config();
+            config.pool_active_limit = Some(limit);
+            let error = config.validate().unwrap_err();
+            assert!(error.contains("POOL_ACTIVE_LIMIT"), "{}", error);
+        }
+
+        let mut config = test_config();
+        config.pool_active_limit = Some(3);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn upstream_5xx_is_normalized_into_canonical_502() {
+        let mut config = test_config();
+        config.normalize_upstream_errors = true;
+        config.request_id_header = Some("X-Request-Id".into());
+        let proxy = MultiIPProxy::new(config);
+        let mut ctx = RequestContext {
+            request_id: Some("abc".into()),
+            ..Default::default()
+        };
+
+        let mut upstream_response = ResponseHeader::build(503, None).unwrap();
+        upstream_response
+            .insert_header("Transfer-Encoding", "chunked")
+            .unwrap();
+        upstream_response.insert_header("Server", "origin").unwrap();
+        proxy
+            .normalize_upstream_error(&mut upstream_response, &mut ctx)
+            .unwrap();
+
+        assert_eq!(upstream_response.status.as_u16(), 502);
+        let header = |name| {
+            upstream_response
+                .headers
+                .get(name)
+                .map(|v| v.to_str().unwrap())
+        };
+        assert_eq!(header("X-Proxy-Upstream-Status"), Some("503"));
+        assert_eq!(
+            header("Content-Length"),
+            Some(UPSTREAM_ERROR_BODY.len().to_string().as_str())
+        );
+        assert_eq!(header("Transfer-Encoding"), None);
+        assert_eq!(header("Server"), None);
+        assert_eq!(header("X-Request-Id"), Some("abc"));
+        assert_eq!(ctx.normalized_upstream_status, Some(503));
+
+        let mut upstream_response = ResponseHeader::build(404, None).unwrap();
+        let mut ctx = RequestContext::default();
+        proxy
+            .normalize_upstream_error(&mut upstream_response, &mut ctx)
+            .unwrap();
+        assert_eq!(upstream_response.status.as_u16(), 404);
+        assert_eq!(ctx.normalized_upstream_status, None);
+    }
+
+    #[test]
+    fn upstream_5xx_is_relayed_when_normalizing_is_off() {
+        let proxy = MultiIPProxy::new(test_config());
+        let mut ctx = RequestContext::default();
+        let mut upstream_response = ResponseHeader::build(503, None).unwrap();
+        upstream_response.insert_header("Server", "origin").unwrap();
+        proxy
+            .normalize_upstream_error(&mut upstream_response, &mut ctx)
+            .unwrap();
+
+        assert_eq!(upstream_response.status.as_u16(), 503);
+        assert_eq!(upstream_response.headers.get("Server").unwrap(), "origin");
+        assert!(upstream_response
+            .headers
+            .get("X-Proxy-Upstream-Status")
+            .is_none());
+        assert_eq!(ctx.normalized_upstream_status, None);
+    }
+
+    #[test]
+    fn response_body_is_dropped_only_for_head() {
+        let head = RequestHeader::build("HEAD", b"/", None).unwrap();
+        assert!(drops_response_body(&head));
+        assert!(!drops_response_body(&get("/")));
+    }
+
+    fn request_with_host(version: Version, host: Option<&str>) -> RequestHeader {
+        let mut request = get("/");
+        request.set_version(version);
+        if let Some(host) = host {
+            request.insert_header("Host", host).unwrap();
+        }
+        request
+    }
+
+    #[test]
+    fn missing_host_is_rejected_only_for_http11() {
+        let proxy = MultiIPProxy::new(test_config());
+        assert!(proxy.rejects_missing_host(&request_with_host(Version::HTTP_11, None)));
+        assert!(proxy.rejects_missing_host(&request_with_host(Version::HTTP_11, Some(""))));
+        assert!(proxy.rejects_missing_host(&request_with_host(Version::HTTP_11, Some(" "))));
+        assert!(
+            !proxy.rejects_missing_host(&request_with_host(Version::HTTP_11, Some("example.com")))
+        );
+        assert!(!proxy.rejects_missing_host(&request_with_host(Version::HTTP_10, None)));
+
+        let mut absolute = request_with_host(Version::HTTP_11, None);
+        absolute.set_uri("http://example.com/".parse().unwrap());
+        assert!(!proxy.rejects_missing_host(&absolute));
+
+        let mut config = test_config();
+        config.require_host_header = false;
+        let proxy = MultiIPProxy::new(config);
+        assert!(!proxy.rejects_missing_host(&request_with_host(Version::HTTP_11, None)));
+        assert!(!proxy.rejects_missing_host(&request_with_host(Version::HTTP_11, Some(""))));
+    }
+
+    fn maintenance(status: u16, location: Option<&str>) -> MaintenanceResponse {
+        MaintenanceResponse {
+            status,
+            body: "Back soon".into(),
+            location: location.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn validate_requires_location_for_maintenance_redirects() {
+        let mut config = test_config();
+        config.maintenance = Some(maintenance(302, None));
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .contains("MAINTENANCE_LOCATION"));
+
+        config.maintenance = Some(maintenance(302, Some("https://status.example.com/")));
+        assert_eq!(config.validate(), Ok(()));
+        config.maintenance = Some(maintenance(503, None));
+        assert_eq!(config.validate(), Ok(()));
+        config.maintenance = Some(maintenance(200, None));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn maintenance_response_carries_status_body_and_location() {
+        let redirect = maintenance(302, Some("https://status.example.com/"));
+        let (response, body) =
+            build_maintenance_response(&redirect, Some(("X-Request-Id", "abc"))).unwrap();
+        assert_eq!(response.status.as_u16(), 302);
+        assert_eq!(body, "Back soon");
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "https://status.example.com/"
+        );
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "9");
+        assert_eq!(response.headers.get("X-Request-Id").unwrap(), "abc");
+
+        let (response, body) = build_maintenance_response(&maintenance(503, None), None).unwrap();
+        assert_eq!(response.status.as_u16(), 503);
+        assert_eq!(body, "Back soon");
+        assert!(response.headers.get("Location").is_none());
+        assert!(response.headers.get("X-Request-Id").is_none());
+    }
+
+    /// Records every call as `kind name value labels` for assertions.
+    #[derive(Default)]
+    struct RecordingMetrics {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl RecordingMetrics {
+        fn record(&self, kind: &str, name: &str, value: f64, labels: &[(&str, &str)]) {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            self.calls.lock().unwrap().push(format!(
+                "{} {} {} {}",
+                kind,
+                name,
+                value,
+                labels.join(",")
+            ));
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.calls.lock().unwrap())
+        }
+    }
+
+    impl MetricsSink for RecordingMetrics {
+        fn incr_counter(&self, name: &str, labels: &[(&str, &str)]) {
+            self.record("counter", name, 1.0, labels);
+        }
+
+        fn set_gauge(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+            self.record("gauge", name, value, labels);
+        }
+
+        fn observe_histogram(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+            self.record("histogram", name, value, labels);
+        }
+    }
+
+    #[test]
+    fn record_outcome_emits_labelled_metrics() {
+        let mut proxy = MultiIPProxy::new(test_config());
+        let metrics = Arc::new(RecordingMetrics::default());
+        proxy.metrics = metrics.clone();
+
+        proxy.egress_stats.request_started("10.0.0.2");
+        let ctx = RequestContext {
+            connect_duration: Some(Duration::from_millis(5)),
+            upstream_duration: Some(Duration::from_millis(20)),
+            ttfb: Some(Duration::from_millis(12)),
+            ..Default::default()
+        };
+        proxy.record_outcome("10.0.0.2", 200, &ctx);
+        assert_eq!(
+            metrics.take(),
+            [
+                "gauge proxy_active_requests 0 ip=10.0.0.2",
+                "counter proxy_requests_total 1 ip=10.0.0.2,status=2xx",
+                "histogram proxy_upstream_latency_ms 25 ip=10.0.0.2",
+                "histogram proxy_upstream_ttfb_ms 12 ip=10.0.0.2",
+            ]
+        );
+
+        // Failed attempts have no timings, and a normalized error counts as the upstream's
+        proxy.egress_stats.request_started("10.0.0.1");
+        proxy.record_outcome("10.0.0.1", 0, &RequestContext::default());
+        let normalized = RequestContext {
+            normalized_upstream_status: Some(503),
+            ..Default::default()
+        };
+        proxy.egress_stats.request_started("10.0.0.1");
+        proxy.record_outcome("10.0.0.1", 502, &normalized);
+        assert_eq!(
+            metrics.take(),
+            [
+                "gauge proxy_active_requests 0 ip=10.0.0.1",
+                "counter proxy_requests_total 1 ip=10.0.0.1,status=failed",
+                "gauge proxy_active_requests 0 ip=10.0.0.1",
+                "counter proxy_requests_total 1 ip=10.0.0.1,status=5xx",
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_override_is_keyed_by_egress_ip_and_host() {
+        let mut config = test_config();
+        config.dns_overrides = DnsOverrides::from([
+            (
+                ("10.0.0.1".to_string(), "api.example.com".to_string()),
+                "192.0.2.10".parse().unwrap(),
+            ),
+            (
+                ("10.0.0.2".to_string(), "api.example.com".to_string()),
+                "192.0.2.20".parse().unwrap(),
+            ),
+        ]);
+        let proxy = MultiIPProxy::new(config);
+
+        assert_eq!(
+            proxy.resolve_override("10.0.0.1", "api.example.com"),
+            Some("192.0.2.10".parse().unwrap())
+        );
+        assert_eq!(
+            proxy.resolve_override("10.0.0.2", "API.Example.com"),
+            Some("192.0.2.20".parse().unwrap())
+        );
+        assert_eq!(proxy.resolve_override("10.0.0.3", "api.example.com"), None);
+        assert_eq!(proxy.resolve_override("10.0.0.1", "www.example.com"), None);
+    }
+
+    #[test]
+    fn slow_ttfb_needs_to_exceed_the_threshold() {
+        let mut config = test_config();
+        config.slow_ttfb_threshold = Some(Duration::from_millis(500));
+        let proxy = MultiIPProxy::new(config);
+        assert!(!proxy.is_slow_ttfb(Duration::from_millis(499)));
+        assert!(!proxy.is_slow_ttfb(Duration::from_millis(500)));
+        assert!(proxy.is_slow_ttfb(Duration::from_millis(501)));
+
+        let proxy = MultiIPProxy::new(test_config());
+        assert!(!proxy.is_slow_ttfb(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn concurrency_limiter_caps_each_principal() {
+        let limiter = ConcurrencyLimiter::new(2);
+        assert!(limiter.try_acquire("alice"));
+        assert!(limiter.try_acquire("alice"));
+        assert!(!limiter.try_acquire("alice"));
+
+        // Other principals have their own slots
+        assert!(limiter.try_acquire("bob"));
+        assert!(limiter.try_acquire("bob"));
+        assert!(!limiter.try_acquire("bob"));
+
+        limiter.release("alice");
+        assert!(limiter.try_acquire("alice"));
+        assert!(!limiter.try_acquire("alice"));
+        assert!(!limiter.try_acquire("bob"));
+
+        // Releasing everything drops the entry, and stray releases are harmless
+        limiter.release("bob");
+        limiter.release("bob");
+        limiter.release("bob");
+        assert!(!limiter.active.lock().unwrap().contains_key("bob"));
+        assert!(limiter.try_acquire("bob"));
+    }
+
+    #[test]
+    fn client_body_deadline_only_cuts_off_unfinished_bodies() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(2);
+        let mut ctx = RequestContext::default();
+        assert!(!ctx.client_body_overdue(false, deadline));
+
+        ctx.client_body_deadline = Some(deadline);
+        // Finished before the deadline
+        assert!(!ctx.client_body_overdue(false, start));
+        assert!(!ctx.client_body_overdue(true, start + Duration::from_secs(1)));
+        // Still sending at or after it
+        assert!(ctx.client_body_overdue(false, deadline));
+        assert!(ctx.client_body_overdue(false, deadline + Duration::from_secs(1)));
+        assert!(!ctx.client_body_overdue(true, deadline + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn retry_header_counts_failovers() {
+        let mut config = test_config();
+        config.expose_retry_header = true;
+        let proxy = MultiIPProxy::new(config);
+        let mut ctx = RequestContext::default();
+
+        for (attempts, retries) in [(1, "0"), (2, "1"), (3, "2")] {
+            ctx.upstream_attempts = attempts;
+            assert_eq!(proxy.retry_header(&ctx).as_deref(), Some(retries));
+        }
+
+        let proxy = MultiIPProxy::new(test_config());
+        assert_eq!(proxy.retry_header(&ctx), None);
+    }
+
+    #[test]
+    fn pooled_connections_retire_past_max_age() {
+        let mut config = test_config();
+        config.max_conn_age = Some(Duration::from_secs(60));
+        let proxy = MultiIPProxy::new(config);
+        assert!(!proxy.retires_connection(true, Duration::from_secs(59)));
+        assert!(!proxy.retires_connection(true, Duration::from_secs(60)));
+        assert!(proxy.retires_connection(true, Duration::from_secs(61)));
+        assert!(!proxy.retires_connection(false, Duration::from_secs(61)));
+
+        let proxy = MultiIPProxy::new(test_config());
+        assert!(!proxy.retires_connection(true, Duration::from_secs(86400)));
+    }
 }